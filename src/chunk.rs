@@ -1,16 +1,99 @@
 use anyhow::{Context, Result};
 use sha2::{Digest, Sha256};
-use std::{fs::File, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}};
+use ignore::gitignore::Gitignore;
+use once_cell::sync::Lazy;
+use std::{collections::HashMap, fs::File, io::{Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use crate::{index::FileStamp, relpath::RelPath};
 
 pub const CHUNK_SIZE: usize = 1024 * 1024; // 1 MiB
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct ChunkInfo {
     pub index: u64,
+    pub offset: u64,
     pub hash: [u8; 32],
     pub size: u32,
 }
 
+/// How files are split into chunks. Both peers must use the same scheme for a
+/// session, so the client proposes one in `Hello` and the server echoes what it accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, serde::Serialize, serde::Deserialize)]
+pub enum Chunking {
+    /// Fixed `CHUNK_SIZE` blocks at multiples of the chunk size.
+    #[default]
+    Fixed,
+    /// Content-defined boundaries (FastCDC 2020); sizes in bytes.
+    FastCdc { min: u32, avg: u32, max: u32 },
+}
+
+impl Chunking {
+    pub const FASTCDC_DEFAULT: Chunking = Chunking::FastCdc { min: 256 * 1024, avg: 1024 * 1024, max: 4 * 1024 * 1024 };
+
+    /// Smallest FastCDC sizes we chunk with. Tiny chunks would have a server re-hash and
+    /// index its whole share at a client's request for a cost of ~56 bytes per chunk.
+    pub const FASTCDC_MIN_FLOOR: u32 = 64 * 1024;
+    pub const FASTCDC_AVG_FLOOR: u32 = 256 * 1024;
+
+    /// Clamp peer-proposed FastCDC sizes into the range the chunker accepts, and no
+    /// smaller than the floors above.
+    pub fn sanitized(self) -> Chunking {
+        use fastcdc::v2020::{AVERAGE_MAX, MAXIMUM_MAX, MAXIMUM_MIN, MINIMUM_MAX};
+        match self {
+            Chunking::Fixed => Chunking::Fixed,
+            Chunking::FastCdc { min, avg, max } => {
+                let min = min.clamp(Self::FASTCDC_MIN_FLOOR, MINIMUM_MAX);
+                let avg = avg.clamp(Self::FASTCDC_AVG_FLOOR, AVERAGE_MAX).max(min);
+                let max = max.clamp(MAXIMUM_MIN, MAXIMUM_MAX).max(avg);
+                Chunking::FastCdc { min, avg, max }
+            }
+        }
+    }
+
+    /// Most chunks a file of `size` bytes can have: only the last may be under the minimum.
+    pub fn max_count(&self, size: u64) -> u64 {
+        let min = match self { Chunking::Fixed => CHUNK_SIZE as u64, Chunking::FastCdc { min, .. } => (*min as u64).max(1) };
+        size.div_ceil(min)
+    }
+
+    /// Rough chunk count for a file size, used where we list files without hashing.
+    pub fn estimate_count(&self, size: u64) -> u64 {
+        let avg = match self { Chunking::Fixed => CHUNK_SIZE as u64, Chunking::FastCdc { avg, .. } => *avg as u64 };
+        if size == 0 { 0 } else { ((size - 1) / avg) + 1 }
+    }
+}
+
+impl std::str::FromStr for Chunking {
+    type Err = anyhow::Error;
+
+    /// `fixed`, `fastcdc`, or `fastcdc:MIN:AVG:MAX` with sizes in KiB.
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_ascii_lowercase();
+        if s == "fixed" { return Ok(Chunking::Fixed); }
+        if s == "fastcdc" || s == "cdc" { return Ok(Chunking::FASTCDC_DEFAULT); }
+        if let Some(rest) = s.strip_prefix("fastcdc:") {
+            let sizes: Vec<u32> = rest.split(':').map(|p| p.parse::<u32>()).collect::<Result<_, _>>()
+                .map_err(|_| anyhow::anyhow!("invalid FastCDC sizes: {rest}"))?;
+            if let [min, avg, max] = sizes[..] {
+                if !(min <= avg && avg <= max) { anyhow::bail!("FastCDC sizes must satisfy min <= avg <= max"); }
+                let kib = |n: u32| n.checked_mul(1024).ok_or_else(|| anyhow::anyhow!("FastCDC size {n} KiB is too large"));
+                return Ok(Chunking::FastCdc { min: kib(min)?, avg: kib(avg)?, max: kib(max)? }.sanitized());
+            }
+            anyhow::bail!("expected fastcdc:MIN:AVG:MAX (KiB)");
+        }
+        anyhow::bail!("unknown chunking {s:?} (expected fixed, fastcdc or fastcdc:MIN:AVG:MAX)")
+    }
+}
+
+impl std::fmt::Display for Chunking {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Chunking::Fixed => write!(f, "fixed"),
+            Chunking::FastCdc { min, avg, max } => write!(f, "fastcdc:{}:{}:{}", min / 1024, avg / 1024, max / 1024),
+        }
+    }
+}
+
 pub fn hash_bytes(data: &[u8]) -> [u8; 32] {
     let mut hasher = Sha256::new();
     hasher.update(data);
@@ -31,7 +114,14 @@ pub fn hash_file(path: &Path) -> Result<[u8; 32]> {
     Ok(hasher.finalize().into())
 }
 
-pub fn chunk_file(path: &Path) -> Result<Vec<ChunkInfo>> {
+pub fn chunk_file(path: &Path, chunking: Chunking) -> Result<Vec<ChunkInfo>> {
+    match chunking {
+        Chunking::Fixed => chunk_file_fixed(path),
+        Chunking::FastCdc { min, avg, max } => chunk_file_cdc(path, min, avg, max),
+    }
+}
+
+fn chunk_file_fixed(path: &Path) -> Result<Vec<ChunkInfo>> {
     let mut f = File::open(path).with_context(|| format!("open file {path:?}"))?;
     let len = f.metadata()?.len();
     let mut chunks = Vec::new();
@@ -43,58 +133,311 @@ pub fn chunk_file(path: &Path) -> Result<Vec<ChunkInfo>> {
         let read_len = std::cmp::min(CHUNK_SIZE as u64, len - offset) as usize;
         f.read_exact(&mut buf[..read_len])?;
         let hash = hash_bytes(&buf[..read_len]);
-        chunks.push(ChunkInfo { index, hash, size: read_len as u32 });
+        chunks.push(ChunkInfo { index, offset, hash, size: read_len as u32 });
         offset += read_len as u64;
         index += 1;
     }
     Ok(chunks)
 }
 
-pub fn read_chunk(path: &Path, index: u64) -> Result<Vec<u8>> {
+fn chunk_file_cdc(path: &Path, min: u32, avg: u32, max: u32) -> Result<Vec<ChunkInfo>> {
+    let f = File::open(path).with_context(|| format!("open file {path:?}"))?;
+    let reader = std::io::BufReader::with_capacity(max as usize, f);
+    let mut chunks = Vec::new();
+    for (index, c) in fastcdc::v2020::StreamCDC::new(reader, min, avg, max).enumerate() {
+        let c = c.map_err(|e| anyhow::anyhow!("chunking {path:?}: {e}"))?;
+        chunks.push(ChunkInfo { index: index as u64, offset: c.offset, hash: hash_bytes(&c.data), size: c.length as u32 });
+    }
+    Ok(chunks)
+}
+
+/// Read `size` bytes at `offset`; shorter if the file ends first.
+pub fn read_range(path: &Path, offset: u64, size: u32) -> Result<Vec<u8>> {
     let mut f = File::open(path)?;
-    let start = index * CHUNK_SIZE as u64;
-    f.seek(SeekFrom::Start(start))?;
-    let mut buf = vec![0u8; CHUNK_SIZE];
-    let n = f.read(&mut buf)?;
-    buf.truncate(n);
+    f.seek(SeekFrom::Start(offset))?;
+    let mut buf = Vec::with_capacity(size as usize);
+    f.take(size as u64).read_to_end(&mut buf)?;
     Ok(buf)
 }
 
-pub fn write_chunk(path: &Path, index: u64, data: &[u8]) -> Result<()> {
+pub fn write_at(path: &Path, offset: u64, data: &[u8]) -> Result<()> {
     use std::io::{Seek, Write};
-    let mut f = if path.exists() { std::fs::OpenOptions::new().read(true).write(true).open(path)? } else { std::fs::OpenOptions::new().create(true).write(true).open(path)? };
-    let start = index * CHUNK_SIZE as u64;
-    f.seek(SeekFrom::Start(start))?;
+    let mut f = if path.exists() { std::fs::OpenOptions::new().read(true).write(true).open(path)? } else { std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(path)? };
+    f.seek(SeekFrom::Start(offset))?;
     f.write_all(data)?;
     Ok(())
 }
 
-pub fn rel_paths_in_dir(dir: &Path) -> Result<Vec<PathBuf>> {
-    use walkdir::{DirEntry, WalkDir};
+/// Per-directory ignore file, gitignore syntax (negation, anchors, dir-only patterns).
+pub const IGNORE_FILE: &str = ".leafsyncignore";
+
+fn is_internal_dir_name(name: &str) -> bool {
+    matches!(name, ".leafsync_tmp" | ".leafsync_trash" | ".git")
+}
+
+/// What a share walk does with symbolic links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Sync the link itself (its target string), never what it points to.
+    #[default]
+    Link,
+    /// Sync what the link points to as a regular file or directory, as long as
+    /// the target resolves inside the share root.
+    Follow,
+    /// Leave links out of the sync entirely.
+    Skip,
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "link" => Ok(SymlinkPolicy::Link),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            other => anyhow::bail!("unknown symlink policy {other:?} (expected link, follow or skip)"),
+        }
+    }
+}
+
+impl std::fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self { SymlinkPolicy::Link => "link", SymlinkPolicy::Follow => "follow", SymlinkPolicy::Skip => "skip" })
+    }
+}
+
+/// Regular files, directories and (under `SymlinkPolicy::Link`) symlinks with their
+/// targets, relative to the share root.
+#[derive(Default)]
+pub struct ShareEntries {
+    pub files: Vec<PathBuf>,
+    pub dirs: Vec<PathBuf>,
+    pub links: Vec<(PathBuf, String)>,
+}
+
+impl ShareEntries {
+    /// Every entry's relative path, `/`-separated.
+    pub fn rel_strings(&self) -> impl Iterator<Item = String> + '_ {
+        self.files.iter().chain(&self.dirs).chain(self.links.iter().map(|(p, _)| p))
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+    }
+}
+
+/// Root every directory is compared by: directories only differ in existence.
+pub fn dir_root() -> [u8; 32] {
+    hash_bytes(b"directory\0")
+}
+
+/// Root a symlink is compared by: a hash of its target, kept apart from file roots.
+pub fn link_root(target: &str) -> [u8; 32] {
+    hash_bytes(format!("symlink\0{target}").as_bytes())
+}
+
+/// Does `path` (or, if it does not exist yet, its nearest existing ancestor) resolve
+/// inside `root` once every symlink along the way is followed?
+pub fn resolves_within(root: &Path, path: &Path) -> bool {
+    let Ok(root) = root.canonicalize() else { return false };
+    let mut p = path;
+    loop {
+        if let Ok(real) = p.canonicalize() { return real.starts_with(&root); }
+        match p.parent() { Some(parent) => p = parent, None => return false }
+    }
+}
+
+pub fn walk_share(dir: &Path, symlinks: SymlinkPolicy) -> Result<ShareEntries> {
+    use ignore::{DirEntry, WalkBuilder};
 
     fn is_ignored_dir(e: &DirEntry) -> bool {
-        if !e.file_type().is_dir() { return false; }
-        let name = e.file_name().to_string_lossy();
-        matches!(name.as_ref(), ".leafsync_tmp" | ".leafsync_trash" | ".git")
-    }
-
-    let mut out = Vec::new();
-    for e in WalkDir::new(dir)
-        .into_iter()
-        .filter_entry(|e| !is_ignored_dir(e))
-        .filter_map(|e| e.ok())
-    {
-        if e.file_type().is_file() {
-            let p = e.path().to_path_buf();
-            if let Ok(rp) = p.strip_prefix(dir) {
-                let rel_str = rp.to_string_lossy().replace('\\', "/");
-                // Skip internal/temp artifacts
-                if rel_str.ends_with(".part") || rel_str.contains("/~$") {
+        if !e.file_type().map(|t| t.is_dir()).unwrap_or(false) { return false; }
+        is_internal_dir_name(&e.file_name().to_string_lossy())
+    }
+
+    // Only .leafsyncignore files inside the share apply; .gitignore, hidden-file and
+    // parent-directory rules are deliberately off so the share is synced as-is.
+    let follow = symlinks == SymlinkPolicy::Follow;
+    let root = dir.to_path_buf();
+    let walker = WalkBuilder::new(dir)
+        .standard_filters(false)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .follow_links(follow)
+        // A followed link must land inside the share; anything else is never read
+        .filter_entry(move |e| {
+            let escapes = follow && e.path_is_symlink() && !resolves_within(&root, e.path());
+            !is_ignored_dir(e) && !escapes
+        })
+        .build();
+    let mut out = ShareEntries::default();
+    for e in walker.filter_map(|e| e.ok()) {
+        let Some(ft) = e.file_type() else { continue };
+        let as_link = ft.is_symlink() && symlinks == SymlinkPolicy::Link;
+        if !ft.is_file() && !ft.is_dir() && !as_link { continue; }
+        let Ok(rp) = e.path().strip_prefix(dir) else { continue };
+        if rp.as_os_str().is_empty() { continue; }
+        let rel_str = rp.to_string_lossy().replace('\\', "/");
+        // Skip internal/temp artifacts
+        if rel_str.ends_with(".part") || rel_str.contains("/~$") {
+            continue;
+        }
+        if ft.is_dir() {
+            out.dirs.push(rp.to_path_buf());
+        } else if as_link {
+            let Ok(target) = std::fs::read_link(e.path()) else { continue };
+            out.links.push((rp.to_path_buf(), target.to_string_lossy().to_string()));
+        } else {
+            out.files.push(rp.to_path_buf());
+        }
+    }
+    Ok(out)
+}
+
+/// `walk_share` for just the given paths (e.g. ones a watcher reported) instead of the
+/// whole tree. Paths that are gone, ignored, not a valid `RelPath`, or left out by the
+/// symlink policy are skipped; directories are listed but not descended into.
+pub fn entries_for<'a>(dir: &Path, rels: impl IntoIterator<Item = &'a String>, symlinks: SymlinkPolicy) -> ShareEntries {
+    let mut out = ShareEntries::default();
+    for rel in rels {
+        if RelPath::parse(rel).is_err() || is_ignored(dir, rel) { continue; }
+        let abs = dir.join(rel);
+        let Ok(meta) = std::fs::symlink_metadata(&abs) else { continue };
+        if meta.file_type().is_symlink() {
+            match symlinks {
+                SymlinkPolicy::Link => {
+                    if let Ok(target) = std::fs::read_link(&abs) { out.links.push((PathBuf::from(rel), target.to_string_lossy().to_string())); }
                     continue;
                 }
-                out.push(rp.to_path_buf());
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Follow if !resolves_within(dir, &abs) => continue,
+                SymlinkPolicy::Follow => {}
             }
         }
+        match std::fs::metadata(&abs) {
+            Ok(m) if m.is_dir() => out.dirs.push(PathBuf::from(rel)),
+            Ok(m) if m.is_file() => out.files.push(PathBuf::from(rel)),
+            _ => {}
+        }
+    }
+    out
+}
+
+/// A compiled .leafsyncignore and the stamp of the file it was compiled from.
+type CachedMatcher = (FileStamp, Arc<Gitignore>);
+
+/// Compiled .leafsyncignore files by path.
+static IGNORE_MATCHERS: Lazy<Mutex<HashMap<PathBuf, CachedMatcher>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The matcher for the ignore file at `path`, compiled again only when the file changed.
+fn ignore_matcher(path: &Path) -> Option<Arc<Gitignore>> {
+    let meta = std::fs::metadata(path).ok().filter(|m| m.is_file());
+    let mut cache = IGNORE_MATCHERS.lock().unwrap();
+    let Some(meta) = meta else { cache.remove(path); return None };
+    let st = crate::index::stamp(&meta);
+    if let Some((stamp, gi)) = cache.get(path) {
+        if *stamp == st { return Some(gi.clone()); }
+    }
+    let (gi, _err) = Gitignore::new(path);
+    let gi = Arc::new(gi);
+    cache.insert(path.to_path_buf(), (st, gi.clone()));
+    Some(gi)
+}
+
+/// Check a single relative path against the internal exclusions and every
+/// .leafsyncignore between the share root and the file. Used for peer-supplied
+/// paths that never went through `walk_share`. Whether it is a directory (for
+/// `dir/` rules) is looked up on disk; see `is_ignored_as` when it may not exist yet.
+pub fn is_ignored(root: &Path, rel: &str) -> bool {
+    let is_dir = std::fs::metadata(root.join(rel)).map(|m| m.is_dir()).unwrap_or(false);
+    is_ignored_as(root, rel, is_dir)
+}
+
+/// `is_ignored` for a path known to be a directory (or not), e.g. one a peer is
+/// about to create.
+pub fn is_ignored_as(root: &Path, rel: &str, is_dir: bool) -> bool {
+    let rel = rel.replace('\\', "/");
+    let rel = rel.trim_start_matches('/');
+    if rel.ends_with(".part") || rel.starts_with("~$") || rel.contains("/~$") { return true; }
+    let parts: Vec<&str> = rel.split('/').filter(|p| !p.is_empty()).collect();
+    if parts.iter().take(parts.len().saturating_sub(1)).any(|p| is_internal_dir_name(p)) { return true; }
+    let abs = root.join(rel);
+    // Deeper ignore files override shallower ones, like nested .gitignore files.
+    let mut dir = root.to_path_buf();
+    let mut verdict = false;
+    for part in &parts {
+        if let Some(gi) = ignore_matcher(&dir.join(IGNORE_FILE)) {
+            let m = gi.matched_path_or_any_parents(&abs, is_dir);
+            if m.is_ignore() { verdict = true; } else if m.is_whitelist() { verdict = false; }
+        }
+        dir = dir.join(part);
+    }
+    verdict
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch share with the given ignore files, removed again on drop.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str, ignores: &[(&str, &str)]) -> Scratch {
+            let root = std::env::temp_dir().join(format!("leafsync-test-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            for (dir, rules) in ignores {
+                std::fs::create_dir_all(root.join(dir)).unwrap();
+                std::fs::write(root.join(dir).join(IGNORE_FILE), rules).unwrap();
+            }
+            Scratch(root)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn ignore_dir_rule_matches_the_directory_itself() {
+        let share = Scratch::new("dir-rule", &[("", "target/\n")]);
+        assert!(is_ignored_as(&share.0, "target", true));
+        assert!(!is_ignored_as(&share.0, "target", false));
+        assert!(is_ignored_as(&share.0, "a/target/out.o", false));
+        std::fs::create_dir_all(share.0.join("target")).unwrap();
+        assert!(is_ignored(&share.0, "target"));
+        assert!(is_ignored(&share.0, "target/out.o"));
+    }
+
+    #[test]
+    fn ignore_negation_and_nested_override() {
+        let share = Scratch::new("negation", &[("", "*.log\n!keep.log\n"), ("sub", "!*.log\n")]);
+        assert!(is_ignored(&share.0, "debug.log"));
+        assert!(!is_ignored(&share.0, "keep.log"));
+        assert!(is_ignored(&share.0, "other/debug.log"));
+        assert!(!is_ignored(&share.0, "sub/debug.log"));
+        assert!(!is_ignored(&share.0, "notes.txt"));
+        // An edited ignore file takes effect without a restart
+        std::fs::write(share.0.join(IGNORE_FILE), "*.txt\n").unwrap();
+        assert!(is_ignored(&share.0, "notes.txt"));
+        assert!(!is_ignored(&share.0, "debug.log"));
+    }
+
+    #[test]
+    fn sanitized_enforces_floors() {
+        let tiny = Chunking::FastCdc { min: 64, avg: 256, max: 1024 }.sanitized();
+        let Chunking::FastCdc { min, avg, max } = tiny else { panic!("{tiny:?}") };
+        assert_eq!((min, avg), (Chunking::FASTCDC_MIN_FLOOR, Chunking::FASTCDC_AVG_FLOOR));
+        assert!(max >= avg);
+        assert_eq!(Chunking::FASTCDC_DEFAULT.sanitized(), Chunking::FASTCDC_DEFAULT);
+        assert_eq!(Chunking::Fixed.sanitized(), Chunking::Fixed);
+    }
+
+    #[test]
+    fn parse_rejects_overflowing_sizes() {
+        assert!("fastcdc:4194304:4194304:4194304".parse::<Chunking>().is_err());
+        assert!("fastcdc:1:2:4194304".parse::<Chunking>().is_err());
+        assert_eq!("fastcdc:256:1024:4096".parse::<Chunking>().unwrap(), Chunking::FASTCDC_DEFAULT);
+        assert_eq!("fastcdc:1:1:1".parse::<Chunking>().unwrap().to_string(), "fastcdc:64:256:256");
     }
-    Ok(out)
 }
@@ -0,0 +1,216 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::{Path, PathBuf}};
+
+use crate::protocol::Tombstone;
+
+/// Last Merkle root both sides agreed on for each file, per (peer, local folder).
+/// A side whose current root differs from this base has changed the file since the
+/// last sync; if both sides differ from it (and from each other) it is a conflict.
+#[derive(Default, Serialize, Deserialize)]
+pub struct SyncHistory {
+    pub entries: HashMap<String, [u8; 32]>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+fn history_path(peer: &str, folder: &Path) -> Result<PathBuf> {
+    let dir = crate::identity::state_dir()?.join("history");
+    fs::create_dir_all(&dir)?;
+    let folder = folder.canonicalize().unwrap_or_else(|_| folder.to_path_buf());
+    let name = crate::trust::sha256_hex(format!("{}|{}", peer, folder.display()).as_bytes());
+    Ok(dir.join(format!("{}.json", &name[..32])))
+}
+
+impl SyncHistory {
+    pub fn load(peer: &str, folder: &Path) -> Result<SyncHistory> {
+        let p = history_path(peer, folder)?;
+        let mut h: SyncHistory = if p.exists() {
+            let data = fs::read(&p).with_context(|| format!("read {p:?}"))?;
+            serde_json::from_slice(&data).with_context(|| format!("parse {p:?}"))?
+        } else {
+            SyncHistory::default()
+        };
+        h.path = p;
+        Ok(h)
+    }
+
+    pub fn base(&self, rel: &str) -> Option<[u8; 32]> {
+        self.entries.get(rel).copied()
+    }
+
+    pub fn set(&mut self, rel: &str, root: [u8; 32]) {
+        if self.entries.get(rel) != Some(&root) {
+            self.entries.insert(rel.to_string(), root);
+            self.dirty = true;
+        }
+    }
+
+    pub fn remove(&mut self, rel: &str) {
+        if self.entries.remove(rel).is_some() { self.dirty = true; }
+    }
+
+    /// Forget files that exist on neither side any more.
+    pub fn retain(&mut self, live: impl Fn(&str) -> bool) {
+        let before = self.entries.len();
+        self.entries.retain(|k, _| live(k));
+        if self.entries.len() != before { self.dirty = true; }
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        if !self.dirty { return Ok(()); }
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(&self.path, data).with_context(|| format!("write {:?}", self.path))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+/// Deletions a server has applied on behalf of clients, per share, so other
+/// clients can tell "deleted" from "never existed". Expired after `TOMBSTONE_TTL_SECS`.
+#[derive(Default, Serialize, Deserialize)]
+pub struct TombstoneStore {
+    pub entries: HashMap<String, Tombstone>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+pub const TOMBSTONE_TTL_SECS: i64 = 30 * 24 * 3600;
+
+fn tombstone_path(share: &Path) -> Result<PathBuf> {
+    let dir = crate::identity::state_dir()?.join("tombstones");
+    fs::create_dir_all(&dir)?;
+    let share = share.canonicalize().unwrap_or_else(|_| share.to_path_buf());
+    let name = crate::trust::sha256_hex(share.to_string_lossy().as_bytes());
+    Ok(dir.join(format!("{}.json", &name[..32])))
+}
+
+impl TombstoneStore {
+    pub fn load(share: &Path) -> Result<TombstoneStore> {
+        let p = tombstone_path(share)?;
+        let mut s: TombstoneStore = if p.exists() {
+            let data = fs::read(&p).with_context(|| format!("read {p:?}"))?;
+            serde_json::from_slice(&data).with_context(|| format!("parse {p:?}"))?
+        } else {
+            TombstoneStore::default()
+        };
+        s.path = p;
+        let cutoff = chrono::Utc::now().timestamp() - TOMBSTONE_TTL_SECS;
+        s.entries.retain(|_, t| t.deleted_at >= cutoff);
+        Ok(s)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        fs::write(&self.path, data).with_context(|| format!("write {:?}", self.path))
+    }
+}
+
+/// What a pass should do with one file, given local, remote and last-synced roots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    UpToDate,
+    Pull,
+    Push,
+    Conflict,
+    /// The remote deleted a file we have not touched since the last sync.
+    DeleteLocal,
+    /// We deleted a file the remote has not touched since the last sync.
+    DeleteRemote,
+}
+
+/// Only content roots are compared: a change to mtime or mode alone is not a change
+/// here and travels with the file's next content change.
+pub fn decide(local: Option<[u8; 32]>, remote: Option<[u8; 32]>, base: Option<[u8; 32]>) -> Decision {
+    match (local, remote) {
+        (Some(l), Some(r)) if l == r => Decision::UpToDate,
+        // A deletion only wins over an unchanged copy; an edit resurrects the file
+        (None, Some(r)) => if base == Some(r) { Decision::DeleteRemote } else { Decision::Pull },
+        (Some(l), None) => if base == Some(l) { Decision::DeleteLocal } else { Decision::Push },
+        (None, None) => Decision::UpToDate,
+        (Some(l), Some(r)) => {
+            if base == Some(l) { Decision::Pull }
+            else if base == Some(r) { Decision::Push }
+            else { Decision::Conflict }
+        }
+    }
+}
+
+/// `dir/report.txt` -> `dir/report.conflict-<device>-<time>.txt`
+pub fn conflict_name(rel: &str, device: &str) -> String {
+    let ts = chrono::Local::now().format("%Y%m%d_%H%M%S");
+    let (dir, file) = match rel.rfind('/') { Some(i) => (&rel[..=i], &rel[i + 1..]), None => ("", rel) };
+    match file.rfind('.') {
+        Some(i) if i > 0 => format!("{}{}.conflict-{}-{}{}", dir, &file[..i], device, ts, &file[i..]),
+        _ => format!("{}{}.conflict-{}-{}", dir, file, device, ts),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: [u8; 32] = [1; 32];
+    const B: [u8; 32] = [2; 32];
+    const C: [u8; 32] = [3; 32];
+
+    #[test]
+    fn decide_same_content_is_up_to_date() {
+        assert_eq!(decide(Some(A), Some(A), None), Decision::UpToDate);
+        assert_eq!(decide(Some(A), Some(A), Some(B)), Decision::UpToDate);
+        assert_eq!(decide(None, None, Some(A)), Decision::UpToDate);
+        assert_eq!(decide(None, None, None), Decision::UpToDate);
+    }
+
+    #[test]
+    fn decide_one_side_changed() {
+        assert_eq!(decide(Some(A), Some(B), Some(A)), Decision::Pull);
+        assert_eq!(decide(Some(B), Some(A), Some(A)), Decision::Push);
+    }
+
+    #[test]
+    fn decide_both_changed_is_conflict() {
+        assert_eq!(decide(Some(B), Some(C), Some(A)), Decision::Conflict);
+        // Never synced, and the copies differ
+        assert_eq!(decide(Some(A), Some(B), None), Decision::Conflict);
+    }
+
+    #[test]
+    fn decide_deletions() {
+        // Deleted here, untouched there
+        assert_eq!(decide(None, Some(A), Some(A)), Decision::DeleteRemote);
+        // Deleted there, untouched here
+        assert_eq!(decide(Some(A), None, Some(A)), Decision::DeleteLocal);
+    }
+
+    #[test]
+    fn decide_edit_beats_deletion() {
+        assert_eq!(decide(None, Some(B), Some(A)), Decision::Pull);
+        assert_eq!(decide(Some(B), None, Some(A)), Decision::Push);
+        // New on one side only
+        assert_eq!(decide(None, Some(A), None), Decision::Pull);
+        assert_eq!(decide(Some(A), None, None), Decision::Push);
+    }
+
+    #[test]
+    fn conflict_name_keeps_dir_and_extension() {
+        let name = conflict_name("dir/report.txt", "laptop");
+        assert!(name.starts_with("dir/report.conflict-laptop-"), "{name}");
+        assert!(name.ends_with(".txt"), "{name}");
+        let name = conflict_name("archive.tar.gz", "pc");
+        assert!(name.starts_with("archive.tar.conflict-pc-") && name.ends_with(".gz"), "{name}");
+    }
+
+    #[test]
+    fn conflict_name_without_extension() {
+        let name = conflict_name("a/b/Makefile", "pc");
+        assert!(name.starts_with("a/b/Makefile.conflict-pc-"), "{name}");
+        assert!(!name["a/b/Makefile.conflict-pc-".len()..].contains('.'), "{name}");
+        // A leading dot marks a hidden file, not an extension
+        let name = conflict_name(".env", "pc");
+        assert!(name.starts_with(".env.conflict-pc-"), "{name}");
+        assert!(!name.contains('/'), "{name}");
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use directories::ProjectDirs;
 use rcgen::generate_simple_self_signed;
-use rustls::{Certificate, PrivateKey};
+use rustls::{server::{ClientCertVerified, ClientCertVerifier}, Certificate, DistinguishedName, PrivateKey};
 use std::{fs, path::PathBuf, sync::Arc};
 
 /// Returns the app-specific state directory, creating it if needed.
@@ -13,7 +13,17 @@ pub fn state_dir() -> Result<PathBuf> {
     Ok(dir)
 }
 
-/// Load persisted TLS cert/key, or generate and persist new ones.
+/// Short, filename-safe name for this machine, used in conflict copies.
+pub fn device_name() -> String {
+    let raw = std::env::var("COMPUTERNAME").or_else(|_| std::env::var("HOSTNAME")).ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .unwrap_or_default();
+    let name: String = raw.trim().chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect();
+    if name.is_empty() { "local".into() } else { name }
+}
+
+/// Load persisted TLS cert/key, or generate and persist new ones. This is the device's
+/// identity: the server presents it to clients and the client presents it to servers.
 pub fn load_or_generate_cert() -> Result<(Certificate, PrivateKey, Vec<u8>)> {
     let dir = state_dir()?;
     let cert_path = dir.join("server_cert.der");
@@ -36,10 +46,49 @@ pub fn load_or_generate_cert() -> Result<(Certificate, PrivateKey, Vec<u8>)> {
     Ok((Certificate(cert_der.clone()), PrivateKey(key_der), cert_der))
 }
 
+/// SHA-256 fingerprint (hex) of this device's certificate, as peers see and pin it.
+pub fn fingerprint() -> Result<String> {
+    let (_, _, cert_der) = load_or_generate_cert()?;
+    Ok(crate::trust::sha256_hex(&cert_der))
+}
+
+/// Fingerprint of the certificate a client presented, if it presented one.
+pub fn peer_fingerprint(conn: &quinn::Connection) -> Option<String> {
+    let certs = conn.peer_identity()?.downcast::<Vec<Certificate>>().ok()?;
+    certs.first().map(|c| crate::trust::sha256_hex(&c.0))
+}
+
+/// Asks clients for a certificate but takes any self-signed one: who may do what is
+/// decided per share by fingerprint (see `shares::Share::access`), not by a CA. The
+/// handshake still proves the client holds the key for the certificate it sent.
+struct AnyClientCert;
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        // Older clients without an identity can still use shares open to everyone
+        false
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(&self, _end_entity: &Certificate, _intermediates: &[Certificate], _now: std::time::SystemTime) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
 /// Build a QUIC server config using the persisted certificate.
 pub fn make_server_config() -> Result<(quinn::ServerConfig, Vec<u8>)> {
     let (cert, key, cert_der) = load_or_generate_cert()?;
-    let mut server_config = quinn::ServerConfig::with_single_cert(vec![cert], key)?;
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_client_cert_verifier(Arc::new(AnyClientCert))
+        .with_single_cert(vec![cert], key)?;
+    crypto.max_early_data_size = u32::MAX;
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
     let mut transport = quinn::TransportConfig::default();
     transport.max_concurrent_bidi_streams(64u32.into());
     server_config.transport = Arc::new(transport);
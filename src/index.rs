@@ -0,0 +1,146 @@
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{collections::{HashMap, HashSet}, fs, path::{Path, PathBuf}, sync::Mutex, time::{SystemTime, UNIX_EPOCH}};
+
+use crate::chunk::{chunk_file, ChunkInfo, Chunking};
+
+/// Files modified this recently are hashed but not cached: a write landing in the
+/// same mtime tick as our read would otherwise leave a stale entry behind.
+const RACY_WINDOW_NS: i64 = 2_000_000_000;
+
+/// Identity of a file on disk; any change invalidates the cached hashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_ns: i64,
+    pub inode: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub stamp: FileStamp,
+    pub chunking: Chunking,
+    pub root: [u8; 32],
+    pub chunks: Vec<ChunkInfo>,
+}
+
+/// Per-share cache of chunk hashes and Merkle roots, keyed by relative path. A path
+/// keeps one entry per chunking, so clients using fixed and FastCDC chunking on the
+/// same share do not evict each other's hashes.
+#[derive(Default, Serialize, Deserialize)]
+pub struct HashIndex {
+    pub entries: HashMap<String, Vec<IndexEntry>>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+static INDEXES: Lazy<Mutex<HashMap<PathBuf, HashIndex>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn share_key(root: &Path) -> PathBuf {
+    root.canonicalize().unwrap_or_else(|_| root.to_path_buf())
+}
+
+fn index_path(share: &Path) -> Result<PathBuf> {
+    let dir = crate::identity::state_dir()?.join("index");
+    fs::create_dir_all(&dir)?;
+    let name = crate::trust::sha256_hex(share.to_string_lossy().as_bytes());
+    Ok(dir.join(format!("{}.bin", &name[..32])))
+}
+
+fn load(share: &Path) -> HashIndex {
+    let Ok(p) = index_path(share) else { return HashIndex::default() };
+    match fs::read(&p) {
+        Ok(data) => bincode::deserialize(&data).unwrap_or_else(|e| {
+            eprintln!("discarding unreadable hash index {p:?}: {e}");
+            HashIndex::default()
+        }),
+        Err(_) => HashIndex::default(),
+    }
+}
+
+pub fn stamp(meta: &fs::Metadata) -> FileStamp {
+    let mtime_ns = meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0);
+    #[cfg(unix)]
+    let inode = { use std::os::unix::fs::MetadataExt; meta.ino() };
+    #[cfg(not(unix))]
+    let inode = 0;
+    FileStamp { size: meta.len(), mtime_ns, inode }
+}
+
+fn now_ns() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as i64).unwrap_or(0)
+}
+
+/// Chunk hashes and Merkle root for `root/rel`, from the index when the file's
+/// size, mtime and inode are unchanged, otherwise hashed and recorded.
+pub fn chunk_file_cached(root: &Path, rel: &str, chunking: Chunking) -> Result<(Vec<ChunkInfo>, [u8; 32])> {
+    let abs = root.join(rel);
+    let meta = fs::metadata(&abs).with_context(|| format!("stat {abs:?}"))?;
+    let st = stamp(&meta);
+    let share = share_key(root);
+    {
+        let mut all = INDEXES.lock().unwrap();
+        let idx = all.entry(share.clone()).or_insert_with(|| load(&share));
+        if let Some(e) = idx.entries.get(rel).and_then(|v| v.iter().find(|e| e.chunking == chunking)) {
+            if e.stamp == st { return Ok((e.chunks.clone(), e.root)); }
+        }
+    }
+    // Hash outside the lock so concurrent streams are not serialized on one big file
+    let chunks = chunk_file(&abs, chunking)?;
+    let root_hash = crate::merkle::root_hash(&crate::merkle::build_merkle(&chunks));
+    // Re-stat: if the file moved under us, return what we read but do not cache it
+    let after = fs::metadata(&abs).map(|m| stamp(&m)).ok();
+    if after == Some(st) && now_ns() - st.mtime_ns > RACY_WINDOW_NS {
+        let mut all = INDEXES.lock().unwrap();
+        let idx = all.entry(share.clone()).or_insert_with(|| load(&share));
+        // Entries for other chunkings stay only while they describe the same file
+        let kept = idx.entries.entry(rel.to_string()).or_default();
+        kept.retain(|e| e.chunking != chunking && e.stamp == st);
+        kept.push(IndexEntry { stamp: st, chunking, root: root_hash, chunks: chunks.clone() });
+        idx.dirty = true;
+    }
+    Ok((chunks, root_hash))
+}
+
+/// Carry cached entries over a rename; size, mtime and inode survive a rename.
+pub fn rename_entry(root: &Path, from: &str, to: &str) {
+    let share = share_key(root);
+    let mut all = INDEXES.lock().unwrap();
+    if let Some(idx) = all.get_mut(&share) {
+        if let Some(e) = idx.entries.remove(from) {
+            idx.entries.insert(to.to_string(), e);
+            idx.dirty = true;
+        }
+    }
+}
+
+/// Drop entries for paths that no longer exist in the share.
+pub fn retain(root: &Path, live: &HashSet<String>) {
+    let share = share_key(root);
+    let mut all = INDEXES.lock().unwrap();
+    if let Some(idx) = all.get_mut(&share) {
+        let before = idx.entries.len();
+        idx.entries.retain(|k, _| live.contains(k));
+        if idx.entries.len() != before { idx.dirty = true; }
+    }
+}
+
+/// Persist the index for `root` if anything changed since the last flush.
+pub fn flush(root: &Path) -> Result<()> {
+    let share = share_key(root);
+    let data = {
+        let mut all = INDEXES.lock().unwrap();
+        match all.get_mut(&share) {
+            Some(idx) if idx.dirty => { idx.dirty = false; bincode::serialize(&*idx)? }
+            _ => return Ok(()),
+        }
+    };
+    let p = index_path(&share)?;
+    let tmp = p.with_extension("tmp");
+    fs::write(&tmp, data).with_context(|| format!("write {tmp:?}"))?;
+    fs::rename(&tmp, &p).with_context(|| format!("rename {tmp:?}"))
+}
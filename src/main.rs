@@ -1,14 +1,18 @@
 mod net;
 mod chunk;
+mod index;
 mod merkle;
 mod protocol;
+mod relpath;
+mod shares;
 mod syncer;
 mod identity;
 mod trust;
 mod resume;
+mod history;
 mod web;
 mod status;
-// mod watch; // removed watch mode; continuous sync handled by Connect
+mod watch;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
@@ -23,14 +27,39 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Start a QUIC server and serve a folder
+    /// Start a QUIC server and serve a folder, or the named shares of a config file
     Serve {
-        folder: PathBuf,
+        #[arg(required_unless_present = "config")]
+        folder: Option<PathBuf>,
+        /// JSON file declaring named shares: {"shares": {"name": {"path": "...", "symlinks": "link"}}}
+        #[arg(long, conflicts_with_all = ["folder", "file", "symlinks", "read_only", "allow", "allow_read", "xattrs"])]
+        config: Option<PathBuf>,
+        /// Refuse all changes from clients; they can only pull
+        #[arg(long)]
+        read_only: bool,
+        /// Only let clients with this certificate fingerprint in, read-write (repeatable)
+        #[arg(long)]
+        allow: Vec<String>,
+        /// Let clients with this certificate fingerprint in, read-only (repeatable)
+        #[arg(long)]
+        allow_read: Vec<String>,
         #[arg(long, default_value_t = 4455)]
         port: u16,
         /// Sync only a specific file (relative to folder)
         #[arg(long)]
         file: Option<String>,
+        /// Symlinks: link, follow (only links resolving inside the folder) or skip
+        #[arg(long, default_value = "link")]
+        symlinks: chunk::SymlinkPolicy,
+        /// Apply `user.*` extended attributes pushed by clients (Unix only)
+        #[arg(long)]
+        xattrs: bool,
+        /// Largest protocol message to accept, in MiB
+        #[arg(long, default_value_t = 64)]
+        max_frame_mb: usize,
+        /// zstd level for chunks we send (1-22); 0 disables compression for the session
+        #[arg(long, default_value_t = protocol::DEFAULT_ZSTD_LEVEL)]
+        compress_level: i32,
     },
     /// Connect to a peer and sync a folder
     Connect {
@@ -51,10 +80,39 @@ enum Commands {
     /// Number of concurrent download streams (1-16)
     #[arg(long, default_value_t = 4)]
     streams: usize,
+    /// Number of files transferred at once while pulling (1-16)
+    #[arg(long, default_value_t = 4)]
+    parallel_files: usize,
     /// Rate limit in Mbps (omit for unlimited)
     #[arg(long)]
     rate_mbps: Option<f64>,
+    /// Chunking: fixed (1 MiB), fastcdc, or fastcdc:MIN:AVG:MAX in KiB
+    #[arg(long, default_value = "fixed")]
+    chunking: chunk::Chunking,
+    /// Also sync extended attributes (Unix only)
+    #[arg(long)]
+    xattrs: bool,
+    /// Symlinks: link (sync the link itself), follow (sync what it points to, inside the folder only) or skip
+    #[arg(long, default_value = "link")]
+    symlinks: chunk::SymlinkPolicy,
+    /// Share to sync, when the server has several
+    #[arg(long, default_value = "")]
+    share: String,
+    /// pull (never change the server), push (never change the local folder) or both
+    #[arg(long, default_value = "both")]
+    direction: protocol::Direction,
+    /// Seconds between full rescans; local changes are synced as they happen
+    #[arg(long, default_value_t = 30)]
+    rescan_secs: u64,
+    /// Largest protocol message to accept, in MiB
+    #[arg(long, default_value_t = 64)]
+    max_frame_mb: usize,
+    /// zstd level for chunks we send (1-22); 0 disables compression for the session
+    #[arg(long, default_value_t = protocol::DEFAULT_ZSTD_LEVEL)]
+    compress_level: i32,
     },
+    /// Print this device's certificate fingerprint, for a server's allowlist
+    Id,
     /// Manage trusted server fingerprints (TOFU)
     #[command(subcommand)]
     Trust(TrustCmd),
@@ -76,13 +134,31 @@ enum TrustCmd {
 async fn main() -> Result<()> {
     let cli = Cli::parse();
     match cli.command {
-        Commands::Serve { folder, port, file } => {
+        Commands::Serve { folder, config, read_only, allow, allow_read, port, file, symlinks, xattrs, max_frame_mb, compress_level } => {
+            protocol::set_max_frame(max_frame_mb * 1024 * 1024);
+            protocol::set_zstd_level(compress_level);
+            let shares = match (config, folder) {
+                (Some(config), _) => shares::Shares::load(&config)?,
+                (None, Some(folder)) => {
+                    let peers = allow_read.into_iter().map(|fp| (fp, shares::Access::ReadOnly))
+                        .chain(allow.into_iter().map(|fp| (fp, shares::Access::ReadWrite)))
+                        .collect();
+                    shares::Shares::single(shares::Share { path: folder, file, symlinks, read_only, xattrs, peers })
+                }
+                (None, None) => unreachable!("clap requires a folder or --config"),
+            };
             println!("LeafSync server starting on 0.0.0.0:{port}");
-            net::run_server_filtered(folder, port, file).await?;
+            net::run_server_shares(shares, port).await?;
         }
-        Commands::Connect { addr, folder, accept_first, fingerprint, file, mirror, streams, rate_mbps } => {
+        Commands::Connect { addr, folder, accept_first, fingerprint, file, mirror, streams, parallel_files, rate_mbps, chunking, xattrs, symlinks, share, direction, rescan_secs, max_frame_mb, compress_level } => {
+            protocol::set_max_frame(max_frame_mb * 1024 * 1024);
+            protocol::set_zstd_level(compress_level);
             println!("LeafSync connecting to {addr}");
-            net::run_client_filtered(addr, folder, accept_first, fingerprint, file, mirror, streams, rate_mbps).await?;
+            let opts = net::SyncOptions { only_file: file, mirror, streams, parallel_files, rate_mbps, chunking, xattrs, symlinks, share, direction, rescan_secs };
+            net::run_client_filtered(addr, folder, accept_first, fingerprint, opts).await?;
+        }
+        Commands::Id => {
+            println!("{}", identity::fingerprint()?);
         }
         Commands::Trust(cmd) => {
             match cmd {
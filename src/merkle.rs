@@ -1,5 +1,7 @@
 use crate::chunk::ChunkInfo;
+use crate::protocol::TreeChild;
 use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct MerkleNode {
@@ -49,23 +51,253 @@ pub fn root_hash(tree: &MerkleTree) -> [u8; 32] {
 
 fn hash_pair(a: [u8; 32], b: [u8; 32]) -> [u8; 32] {
     let mut hasher = Sha256::new();
-    hasher.update(&a);
-    hasher.update(&b);
+    hasher.update(a);
+    hasher.update(b);
     hasher.finalize().into()
 }
 
-// Compute which chunk indices differ by comparing two trees.
-#[allow(dead_code)]
-pub fn diff_chunks(a: &MerkleTree, b: &MerkleTree) -> Vec<u64> {
-    if a.leaves.len() != b.leaves.len() {
-        // lengths differ, request all from b
-        return (0..b.leaves.len() as u64).collect();
-    }
-    let mut diffs = Vec::new();
-    for i in 0..a.leaves.len() {
-        if a.leaves[i].hash != b.leaves[i].hash {
-            diffs.push(i as u64);
+/// Number of levels above the leaves in a tree over `leaves` chunks (0 for one chunk).
+pub fn height(leaves: u64) -> u32 {
+    if leaves <= 1 { 0 } else { 64 - (leaves - 1).leading_zeros() }
+}
+
+/// Nodes at `level` (0 = leaves) of a tree over `leaves` chunks.
+pub fn level_len(leaves: u64, level: u32) -> u64 {
+    if level >= 64 { return leaves.min(1); }
+    leaves.div_ceil(1u64 << level)
+}
+
+/// Hash of node `index` at `level` (0 = leaves), if the tree has it.
+pub fn node_hash(tree: &MerkleTree, level: u32, index: u64) -> Option<[u8; 32]> {
+    let nodes = if level == 0 { &tree.leaves } else { tree.upper.get(level as usize - 1)? };
+    nodes.get(index as usize).map(|n| n.hash)
+}
+
+/// One level of walking a remote tree of `n` leaves against `ours`, built over `local`:
+/// `theirs` are the remote hashes at `level` (> 0) for the nodes in `asked`. Matching
+/// nodes fill their leaves in `found` from `local`; the children of the others come
+/// back, to be asked about one level down.
+pub fn walk_level(ours: &MerkleTree, local: &[ChunkInfo], n: u64, level: u32, asked: &[u64], theirs: &[[u8; 32]], found: &mut [Option<ChunkInfo>]) -> Vec<u64> {
+    let m = local.len() as u64;
+    let mut next = Vec::new();
+    for (&i, &h) in asked.iter().zip(theirs) {
+        let (start, end) = (i << level, (i + 1) << level);
+        // Equal hashes only vouch for the leaves if both sides have as many under the node:
+        // with last-node duplication, [a, b, c] and [a, b, c, c] share every upper hash
+        if end.min(n) == end.min(m) && node_hash(ours, level, i) == Some(h) {
+            for j in start..end.min(n) { found[j as usize] = Some(local[j as usize].clone()); }
+        } else {
+            next.extend([2 * i, 2 * i + 1].into_iter().filter(|&c| c < level_len(n, level - 1)));
+        }
+    }
+    next
+}
+
+/// Merkle tree over a whole share. A directory hashes its children's names and roots,
+/// a subdirectory's root being its own hash, so equal top hashes mean equal shares and
+/// a difference can be chased down one level at a time.
+#[derive(Debug, Default)]
+pub struct ShareTree {
+    /// Children of every directory by name, keyed by directory path ("" is the share)
+    dirs: HashMap<String, BTreeMap<String, TreeChild>>,
+    hashes: HashMap<String, [u8; 32]>,
+}
+
+impl ShareTree {
+    /// `entries` are files and links with their roots; `dirs` lists directories that
+    /// count even when empty. Parents of every entry are implied.
+    pub fn build(entries: impl IntoIterator<Item = (String, [u8; 32])>, dirs: impl IntoIterator<Item = String>) -> ShareTree {
+        let mut tree = ShareTree::default();
+        tree.dirs.insert(String::new(), BTreeMap::new());
+        for d in dirs { tree.add_dir(&d); }
+        for (path, root) in entries {
+            let (parent, name) = split(&path);
+            tree.add_dir(parent);
+            if let Some(children) = tree.dirs.get_mut(parent) {
+                children.insert(name.to_string(), TreeChild { name: name.to_string(), root, dir: false });
+            }
+        }
+        // Deepest first, so every subdirectory is hashed before its parent
+        let mut order: Vec<String> = tree.dirs.keys().cloned().collect();
+        order.sort_by_key(|d| std::cmp::Reverse(if d.is_empty() { 0 } else { d.split('/').count() }));
+        for d in order {
+            let h = dir_hash(tree.dirs[&d].values());
+            tree.hashes.insert(d.clone(), h);
+            if d.is_empty() { continue; }
+            let (parent, name) = split(&d);
+            if let Some(child) = tree.dirs.get_mut(parent).and_then(|c| c.get_mut(name)) { child.root = h; }
+        }
+        tree
+    }
+
+    fn add_dir(&mut self, path: &str) {
+        if path.is_empty() || self.dirs.contains_key(path) { return; }
+        self.dirs.insert(path.to_string(), BTreeMap::new());
+        let (parent, name) = split(path);
+        self.add_dir(parent);
+        if let Some(children) = self.dirs.get_mut(parent) {
+            children.insert(name.to_string(), TreeChild { name: name.to_string(), root: [0u8; 32], dir: true });
         }
     }
-    diffs
+
+    /// Hash of the whole share.
+    pub fn root(&self) -> [u8; 32] {
+        self.hashes.get("").copied().unwrap_or_default()
+    }
+
+    pub fn hash(&self, dir: &str) -> Option<[u8; 32]> {
+        self.hashes.get(dir).copied()
+    }
+
+    /// Children of `dir` in name order; None if it is not a directory here.
+    pub fn children(&self, dir: &str) -> Option<Vec<TreeChild>> {
+        self.dirs.get(dir).map(|c| c.values().cloned().collect())
+    }
+
+    /// Every path below `dir`, directories included.
+    pub fn subtree(&self, dir: &str) -> Vec<String> {
+        let mut out = Vec::new();
+        for child in self.dirs.get(dir).into_iter().flat_map(|c| c.values()) {
+            let path = join(dir, &child.name);
+            if child.dir { out.extend(self.subtree(&path)); }
+            out.push(path);
+        }
+        out
+    }
+}
+
+/// Hash of a directory from its children, which must come in name order.
+pub fn dir_hash<'a>(children: impl IntoIterator<Item = &'a TreeChild>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"tree\0");
+    for c in children {
+        hasher.update(c.name.as_bytes());
+        hasher.update([0u8, c.dir as u8]);
+        hasher.update(c.root);
+    }
+    hasher.finalize().into()
+}
+
+/// `a/b/c` as (`a/b`, `c`); top-level entries have parent "".
+fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// `name` inside `dir`, "" being the share.
+pub fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() { name.to_string() } else { format!("{dir}/{name}") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(tags: &[u8]) -> Vec<ChunkInfo> {
+        tags.iter().enumerate().map(|(i, &t)| ChunkInfo { index: i as u64, offset: i as u64 * 10, hash: [t; 32], size: 10 }).collect()
+    }
+
+    /// Walk `remote` from `local` as `fetch_walked` does; the leaves asked for at level 0
+    /// come from `remote`. Returns the result and which leaves had to be fetched.
+    fn walk(local: &[ChunkInfo], remote: &[ChunkInfo]) -> (Vec<Option<ChunkInfo>>, Vec<u64>) {
+        let (ours, theirs) = (build_merkle(local), build_merkle(remote));
+        let n = remote.len() as u64;
+        let mut found = vec![None; remote.len()];
+        let mut level = height(n).saturating_sub(1);
+        let mut ask: Vec<u64> = (0..level_len(n, level)).collect();
+        while level > 0 && !ask.is_empty() {
+            let hashes: Vec<[u8; 32]> = ask.iter().map(|&i| node_hash(&theirs, level, i).unwrap()).collect();
+            ask = walk_level(&ours, local, n, level, &ask, &hashes, &mut found);
+            level -= 1;
+        }
+        for &i in &ask { found[i as usize] = Some(remote[i as usize].clone()); }
+        (found, ask)
+    }
+
+    fn hashes(found: &[Option<ChunkInfo>]) -> Vec<[u8; 32]> {
+        found.iter().map(|c| c.as_ref().expect("every leaf filled").hash).collect()
+    }
+
+    #[test]
+    fn height_matches_built_tree() {
+        assert_eq!(height(0), 0);
+        assert_eq!(height(1), 0);
+        assert_eq!(height(2), 1);
+        assert_eq!(height(3), 2);
+        assert_eq!(height(4), 2);
+        assert_eq!(height(5), 3);
+        assert_eq!(height(1024), 10);
+        assert_eq!(height(1025), 11);
+        for n in 1..70u8 {
+            let tree = build_merkle(&chunks(&(0..n).collect::<Vec<_>>()));
+            assert_eq!(height(n as u64) as usize, tree.upper.len(), "n = {n}");
+        }
+    }
+
+    #[test]
+    fn level_len_matches_built_tree() {
+        assert_eq!(level_len(0, 0), 0);
+        assert_eq!(level_len(5, 0), 5);
+        assert_eq!(level_len(5, 1), 3);
+        assert_eq!(level_len(5, 2), 2);
+        assert_eq!(level_len(5, 3), 1);
+        assert_eq!(level_len(5, 64), 1);
+        assert_eq!(level_len(0, 64), 0);
+        for n in 1..70u8 {
+            let tree = build_merkle(&chunks(&(0..n).collect::<Vec<_>>()));
+            for (l, nodes) in tree.upper.iter().enumerate() {
+                assert_eq!(level_len(n as u64, l as u32 + 1), nodes.len() as u64, "n = {n}, level = {}", l + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn node_hash_reads_every_level() {
+        let c = chunks(&[1, 2, 3, 4, 5]);
+        let tree = build_merkle(&c);
+        assert_eq!(node_hash(&tree, 0, 4), Some([5; 32]));
+        assert_eq!(node_hash(&tree, 0, 5), None);
+        assert_eq!(node_hash(&tree, 1, 2), Some(hash_pair([5; 32], [5; 32])));
+        assert_eq!(node_hash(&tree, height(5), 0), Some(root_hash(&tree)));
+        assert_eq!(node_hash(&tree, height(5), 1), None);
+        assert_eq!(node_hash(&tree, height(5) + 1, 0), None);
+    }
+
+    #[test]
+    fn walk_fetches_only_changed_leaves() {
+        let local = chunks(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let remote = chunks(&[1, 2, 3, 4, 5, 9, 7, 8]);
+        let (found, fetched) = walk(&local, &remote);
+        // Leaves come in the pairs below the lowest differing node
+        assert_eq!(fetched, vec![4, 5]);
+        assert_eq!(hashes(&found), hashes(&remote.into_iter().map(Some).collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn walk_with_more_remote_leaves() {
+        let local = chunks(&[1, 2, 3, 4]);
+        let remote = chunks(&[1, 2, 3, 4, 5]);
+        let (found, fetched) = walk(&local, &remote);
+        assert_eq!(fetched, vec![4]);
+        assert_eq!(hashes(&found), hashes(&remote.into_iter().map(Some).collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn walk_does_not_trust_duplicated_last_leaf() {
+        // [1, 2, 3] and [1, 2, 3, 3] have the same hashes above the leaves
+        let local = chunks(&[1, 2, 3]);
+        let remote = chunks(&[1, 2, 3, 3]);
+        assert_eq!(node_hash(&build_merkle(&local), 1, 1), node_hash(&build_merkle(&remote), 1, 1));
+        let (found, fetched) = walk(&local, &remote);
+        assert_eq!(fetched, vec![2, 3]);
+        assert_eq!(hashes(&found), hashes(&remote.into_iter().map(Some).collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn walk_with_fewer_remote_leaves() {
+        let local = chunks(&[1, 2, 3, 4, 5]);
+        let remote = chunks(&[1, 2, 3, 4]);
+        let (found, fetched) = walk(&local, &remote);
+        assert!(fetched.is_empty());
+        assert_eq!(hashes(&found), hashes(&remote.into_iter().map(Some).collect::<Vec<_>>()));
+    }
 }
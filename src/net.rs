@@ -2,13 +2,16 @@ use anyhow::Result;
 use quinn::{Endpoint, RecvStream, SendStream, TransportConfig};
 use rustls::{ClientConfig as RustlsClientConfig, RootCertStore};
 use rustls::client::{ServerCertVerifier, ServerCertVerified};
-use std::{net::SocketAddr, path::PathBuf, sync::Arc};
-use tokio::io::AsyncWriteExt;
+use std::{collections::{HashMap, HashSet}, net::SocketAddr, path::{Path, PathBuf}, sync::Arc};
+use tokio::{io::AsyncWriteExt, sync::broadcast};
 
-use crate::{protocol::{Msg, FileSummary}, syncer, chunk::{chunk_file, read_chunk, ChunkInfo, CHUNK_SIZE, rel_paths_in_dir}};
+use crate::{protocol::{cap, BatchItem, Capabilities, Direction, Msg, TreeChild, TreeNode, BATCH_FILE_MAX, BATCH_MAX_BYTES, BATCH_MAX_FILES, WALK_MAX_CHUNKS, WALK_MIN_CHUNKS, EntryKind, ErrorCode, PROTOCOL_MAJOR, PROTOCOL_MINOR, FileSummary, ChunkRange, ProtocolError, Tombstone}, relpath::RelPath, shares::{Access, Share, Shares}, syncer, syncer::move_to_trash, chunk::{chunk_file, dir_root, entries_for, read_range, resolves_within, walk_share, ChunkInfo, Chunking, SymlinkPolicy, is_ignored, is_ignored_as}};
 use crate::identity;
 use crate::trust;
 use crate::resume;
+use crate::index;
+use crate::history::{self, Decision, SyncHistory, TombstoneStore};
+use crate::merkle::{self, ShareTree};
 
 fn normalize_rel(p: &str) -> String {
     let s = p.replace('\\', "/");
@@ -16,30 +19,168 @@ fn normalize_rel(p: &str) -> String {
     s.to_string()
 }
 
-fn is_internal_rel(rel: &str) -> bool {
+pub(crate) fn is_internal_rel(rel: &str) -> bool {
     let r = rel.replace('\\', "/");
-    r.starts_with(".leafsync_tmp/") || r.starts_with(".leafsync_trash/") || r.starts_with(".git/") || r.ends_with(".part")
+    let top = r.split('/').next().unwrap_or("");
+    matches!(top, ".leafsync_tmp" | ".leafsync_trash" | ".git") || r.ends_with(".part")
+}
+
+/// Content reads must never leave the share through a symlink, and never go through
+/// a link at all unless the policy is to follow links.
+fn readable_in_share(folder: &Path, rel: &RelPath, symlinks: SymlinkPolicy) -> bool {
+    let abs = rel.to_path(folder);
+    let is_link = std::fs::symlink_metadata(&abs).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+    if is_link && symlinks != SymlinkPolicy::Follow { return false; }
+    resolves_within(folder, &abs)
+}
+
+/// Writes, renames and deletes act on the entry itself (a link is replaced, not
+/// followed), so only the directory holding it has to resolve inside the share.
+fn writable_in_share(folder: &Path, rel: &RelPath) -> bool {
+    let abs = rel.to_path(folder);
+    abs.parent().map(|p| resolves_within(folder, p)).unwrap_or(false)
+}
+
+/// Summary entry for a regular file. With `roots` its chunks are hashed (normally a hash
+/// index hit); without, the root stays zero and the client asks for `FileMeta`.
+fn file_summary(folder: &Path, rel: &RelPath, meta: &std::fs::Metadata, chunking: Chunking, roots: bool) -> FileSummary {
+    let size = meta.len();
+    let hashed = if roots { index::chunk_file_cached(folder, rel, chunking).ok() } else { None };
+    let (chunk_count, root) = match hashed {
+        Some((chunks, root)) => (chunks.len() as u64, root),
+        None => (chunking.estimate_count(size), [0u8; 32]),
+    };
+    FileSummary { rel_path: rel.to_string(), size, chunk_count, root, attrs: syncer::attrs_from_meta(meta), kind: EntryKind::File }
+}
+
+/// Summary entries for the share (or just `filter`, or the `scope` paths). Hashes files
+/// the index has not seen, so it runs on a blocking thread.
+fn share_summary(folder: &Path, filter: Option<&str>, scope: Option<&HashSet<String>>, symlinks: SymlinkPolicy, chunking: Chunking, caps: &Capabilities) -> Result<Vec<FileSummary>> {
+    let mut files: Vec<FileSummary> = Vec::new();
+    if let Some(f) = filter.and_then(|f| RelPath::parse(f).ok()) {
+        // Fast path: single file metadata
+        let p = f.to_path(folder);
+        let link = std::fs::symlink_metadata(&p).ok().filter(|m| m.file_type().is_symlink()).and_then(|_| std::fs::read_link(&p).ok());
+        if let (Some(target), SymlinkPolicy::Link) = (link, symlinks) {
+            files.push(syncer::link_summary(f.to_string(), target.to_string_lossy().to_string()));
+        } else if let Ok(meta) = std::fs::metadata(&p) {
+            if meta.is_file() && readable_in_share(folder, &f, symlinks) {
+                files.push(file_summary(folder, &f, &meta, chunking, caps.has(cap::SUMMARY_ROOTS)));
+            }
+        }
+    } else if filter.is_none() {
+        // Directory and symlink roots need no hashing; file roots come from the hash index
+        let entries = match scope {
+            Some(paths) => entries_for(folder, paths, symlinks),
+            None => walk_share(folder, symlinks)?,
+        };
+        for rel in entries.dirs.into_iter().filter(|_| caps.has(cap::DIRS)) {
+            files.push(syncer::dir_summary(rel.to_string_lossy().to_string()));
+        }
+        for (rel, target) in entries.links.into_iter().filter(|_| caps.has(cap::SYMLINKS)) {
+            files.push(syncer::link_summary(rel.to_string_lossy().to_string(), target));
+        }
+        for rel in entries.files {
+            let p = folder.join(&rel);
+            if let Ok(meta) = std::fs::metadata(&p) {
+                if meta.is_file() {
+                    let rel_s = rel.to_string_lossy().to_string();
+                    if is_internal_rel(&rel_s) { continue; }
+                    let Ok(rel) = RelPath::parse(&rel_s) else { continue };
+                    files.push(file_summary(folder, &rel, &meta, chunking, caps.has(cap::SUMMARY_ROOTS)));
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// `FileMeta` for a file we serve (all zeros if it is gone); without `with_chunks` the
+/// chunk list is left for the client to walk with `RequestNodes`.
+fn file_meta(folder: &Path, rel: &RelPath, chunking: Chunking, xattrs: bool, with_chunks: bool) -> Result<Msg> {
+    let abs = rel.to_path(folder);
+    let (chunks, root) = if abs.exists() { index::chunk_file_cached(folder, rel, chunking)? } else { (Vec::new(), [0u8; 32]) };
+    let size = std::fs::metadata(&abs).map(|m| m.len()).unwrap_or(0);
+    let attrs = syncer::read_attrs(&abs, xattrs).unwrap_or_default();
+    let chunk_count = chunks.len() as u64;
+    let chunks = if with_chunks { chunks } else { Vec::new() };
+    Ok(Msg::FileMeta { rel_path: rel.to_string(), size, chunk_count, root, chunks, base: None, attrs })
+}
+
+/// Refuse a change on a share this client may only read.
+async fn may_write(send: &mut SendStream, access: Access, rel_path: &str) -> Result<bool> {
+    if access == Access::ReadWrite { return Ok(true); }
+    send_msg(send, &Msg::Error { code: ErrorCode::AccessDenied, message: format!("read-only access; {rel_path} left unchanged") }).await?;
+    Ok(false)
+}
+
+/// Validate a peer-supplied path, or tell the peer why it was rejected.
+async fn peer_path(send: &mut SendStream, raw: &str) -> Result<Option<RelPath>> {
+    match RelPath::parse(raw) {
+        Ok(rel) => Ok(Some(rel)),
+        Err(e) => {
+            eprintln!("Rejected path from peer: {e}");
+            send_msg(send, &Msg::Error { code: ErrorCode::InvalidPath, message: e.to_string() }).await?;
+            Ok(None)
+        }
+    }
 }
 
 #[allow(dead_code)]
 pub async fn run_server(folder: PathBuf, port: u16) -> Result<()> {
-    run_server_filtered(folder, port, None).await
+    run_server_filtered(folder, port, None, SymlinkPolicy::default()).await
+}
+
+pub async fn run_server_filtered(folder: PathBuf, port: u16, only_file: Option<String>, symlinks: SymlinkPolicy) -> Result<()> {
+    run_server_shares(Shares::single(Share { path: folder, file: only_file, symlinks, read_only: false, xattrs: false, peers: Default::default() }), port).await
+}
+
+/// Per share folder: the paths its watcher saw change, for `Subscribe` streams.
+type ChangeFeeds = HashMap<PathBuf, broadcast::Sender<Vec<String>>>;
+
+/// Watch every share so subscribed clients hear about changes as they happen.
+fn watch_shares(shares: &Shares) -> ChangeFeeds {
+    let mut feeds = ChangeFeeds::new();
+    for (name, share) in &shares.shares {
+        if feeds.contains_key(&share.path) { continue; }
+        let (tx, _) = broadcast::channel(64);
+        match crate::watch::FolderWatcher::new(&share.path) {
+            Ok(mut watcher) => {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    loop {
+                        if let Some(paths) = watcher.next_batch(std::time::Duration::from_secs(3600)).await {
+                            // No subscribers is fine
+                            let _ = tx.send(paths.into_iter().collect());
+                        }
+                    }
+                });
+            }
+            Err(e) => eprintln!("Share {name:?}: change notifications unavailable ({e})"),
+        }
+        feeds.insert(share.path.clone(), tx);
+    }
+    feeds
 }
 
-pub async fn run_server_filtered(folder: PathBuf, port: u16, only_file: Option<String>) -> Result<()> {
+/// Serve several named shares on one port; each client picks one in `Hello`.
+pub async fn run_server_shares(shares: Shares, port: u16) -> Result<()> {
     let (mut server_config, cert_der) = identity::make_server_config()?;
     server_config.transport = tuned_transport();
     let addr: SocketAddr = format!("0.0.0.0:{port}").parse().unwrap();
     let endpoint = Endpoint::server(server_config, addr)?;
     println!("Server cert SHA-256 fingerprint: {}", sha256_hex(&cert_der));
     if let Ok(dir) = identity::state_dir() { println!("Identity dir: {}", dir.display()); }
+    for (name, share) in &shares.shares { println!("Share {:?}: {}", name, share.path.display()); }
     println!("Listening on {addr}");
 
+    let feeds = Arc::new(watch_shares(&shares));
+    let shares = Arc::new(shares);
     while let Some(connecting) = endpoint.accept().await {
-        let folder = folder.clone();
-        let only_file = only_file.clone();
+        let shares = shares.clone();
+        let feeds = feeds.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection_server(folder, only_file, connecting).await {
+            if let Err(e) = handle_connection_server(shares, feeds, connecting).await {
                 eprintln!("connection error: {e:?}");
             }
         });
@@ -47,17 +188,26 @@ pub async fn run_server_filtered(folder: PathBuf, port: u16, only_file: Option<S
     Ok(())
 }
 
-async fn handle_connection_server(folder: PathBuf, only_file: Option<String>, conn: quinn::Connecting) -> Result<()> {
+async fn handle_connection_server(shares: Arc<Shares>, feeds: Arc<ChangeFeeds>, conn: quinn::Connecting) -> Result<()> {
     let connection = conn.await?;
-    println!("Peer connected: {}", connection.remote_address());
+    let peer = identity::peer_fingerprint(&connection);
+    println!("Peer connected: {} (client cert: {})", connection.remote_address(), peer.as_deref().unwrap_or("none"));
+    // Set by the Hello on the control stream; chunk streams of this connection use the same share
+    let selected: Arc<std::sync::Mutex<Option<Selected>>> = Arc::new(std::sync::Mutex::new(None));
     // Accept streams forever; each stream can be a control stream (Version/Hello) or a chunk/push stream.
     loop {
         match connection.accept_bi().await {
             Ok((mut send, mut recv)) => {
-                let folder_c = folder.clone();
-                let only_c = only_file.clone();
+                let shares = shares.clone();
+                let feeds = feeds.clone();
+                let selected = selected.clone();
+                let peer = peer.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = handle_server_stream(folder_c, only_c, &mut send, &mut recv).await {
+                    if let Err(e) = handle_server_stream(&shares, &feeds, peer.as_deref(), &selected, &mut send, &mut recv).await {
+                        // Tell the peer why its stream is being dropped
+                        if let Some(pe) = e.downcast_ref::<ProtocolError>() {
+                            let _ = send_msg(&mut send, &Msg::Error { code: pe.code(), message: pe.to_string() }).await;
+                        }
                         eprintln!("stream error: {:?}", e);
                     }
                 });
@@ -69,123 +219,345 @@ async fn handle_connection_server(folder: PathBuf, only_file: Option<String>, co
     Ok(())
 }
 
-async fn handle_server_stream(folder: PathBuf, only_file: Option<String>, send: &mut SendStream, recv: &mut RecvStream) -> Result<()> {
+/// What the last `Hello` on a connection settled, for the streams opened after it.
+#[derive(Clone)]
+struct Selected {
+    share: Share,
+    chunking: Chunking,
+    caps: Capabilities,
+}
+
+async fn handle_server_stream(shares: &Shares, feeds: &ChangeFeeds, peer: Option<&str>, selected: &std::sync::Mutex<Option<Selected>>, send: &mut SendStream, recv: &mut RecvStream) -> Result<()> {
     // Try to read first message and branch
     if let Some(first) = recv_msg(recv).await? {
+        let current = selected.lock().unwrap().clone();
         match first {
-            Msg::Version { major, .. } => {
-                if major != 1 { return Ok(()); }
-                // respond with our version
-                send_msg(send, &Msg::Version { major: 1, minor: 0 }).await?;
-                // expect Hello
-                if let Some(Msg::Hello { folder: _ }) = recv_msg(recv).await? {
+            Msg::Version { major, capabilities, .. } => {
+                // An incompatible peer gets an Error (see handle_connection_server) instead of our Version
+                let caps = Capabilities::negotiate(major, &capabilities)?;
+                send_msg(send, &Msg::Version { major: PROTOCOL_MAJOR, minor: PROTOCOL_MINOR, capabilities: Capabilities::ours().to_vec() }).await?;
+                // expect Hello; adopt the client's chunking (within sane bounds) and echo it back
+                if let Some(Msg::Hello { share: name, chunking, direction, scope }) = recv_msg(recv).await? {
+                    // An unknown or forbidden share gets an Error instead of our Hello
+                    let (share, access) = shares.resolve(&name, peer)?;
+                    // Read-only: a two-way client is held to pulling, a push-only one has nothing to do
+                    let direction = match (access, direction) {
+                        (Access::ReadOnly, Direction::Push) => return Err(ProtocolError::AccessDenied("the share is read-only for this client; push is not allowed".into()).into()),
+                        (Access::ReadOnly, _) => Direction::Pull,
+                        (Access::ReadWrite, d) => d,
+                    };
+                    let chunking = if caps.has(cap::CHUNKING_FASTCDC) { chunking.sanitized() } else { Chunking::Fixed };
+                    *selected.lock().unwrap() = Some(Selected { share: share.clone(), chunking, caps: caps.clone() });
+                    let Share { path: folder, file: only_file, symlinks, xattrs: apply_xattrs, .. } = share;
+                    send_msg(send, &Msg::Hello { share: name, chunking, direction, scope: scope.clone() }).await?;
+                    let scope: Option<HashSet<String>> = scope.filter(|_| caps.has(cap::NOTIFY))
+                        .map(|paths| paths.iter().filter(|p| RelPath::parse(p).is_ok()).map(|p| normalize_rel(p)).collect());
                     let filter_norm: Option<String> = only_file.as_ref().map(|s| normalize_rel(s));
-                    let mut files: Vec<FileSummary> = Vec::new();
-                    if let Some(ref f) = filter_norm {
-                        // Fast path: single file metadata
-                        let p = folder.join(f);
-                        if let Ok(meta) = std::fs::metadata(&p) {
-                            if meta.is_file() {
-                                let size = meta.len();
-                                let cc = if size == 0 { 0 } else { ((size - 1) / CHUNK_SIZE as u64) + 1 };
-                                files.push(FileSummary { rel_path: f.clone(), size, chunk_count: cc, root: [0u8;32] });
-                            }
-                        }
-                    } else {
-                        // Walk directory without hashing
-                        for rel in rel_paths_in_dir(&folder)? {
-                            let p = folder.join(&rel);
-                            if let Ok(meta) = std::fs::metadata(&p) {
-                                if meta.is_file() {
-                                    let rel_s = rel.to_string_lossy().to_string();
-                                    if is_internal_rel(&rel_s) { continue; }
-                                    let size = meta.len();
-                                    let cc = if size == 0 { 0 } else { ((size - 1) / CHUNK_SIZE as u64) + 1 };
-                                    files.push(FileSummary { rel_path: rel_s, size, chunk_count: cc, root: [0u8;32] });
-                                }
-                            }
-                        }
-                    }
-                    println!("Server summary: {} files (filter={:?})", files.len(), filter_norm);
-                    send_msg(send, &Msg::Summary { files }).await?;
+                    let files = {
+                        let (folder, filter, scope, caps) = (folder.clone(), filter_norm.clone(), scope.clone(), caps.clone());
+                        tokio::task::spawn_blocking(move || share_summary(&folder, filter.as_deref(), scope.as_ref(), symlinks, chunking, &caps)).await??
+                    };
+                    // Deletions we applied for other clients, minus paths that have since come back
+                    let present: HashSet<String> = files.iter().map(|f| normalize_rel(&f.rel_path)).collect();
+                    let tombstones: Vec<Tombstone> = TombstoneStore::load(&folder)?.entries.into_values()
+                        .filter(|t| !present.contains(&t.rel_path))
+                        .filter(|t| filter_norm.as_ref().map(|f| *f == t.rel_path).unwrap_or(true))
+                        .filter(|t| scope.as_ref().map(|s| s.contains(&t.rel_path)).unwrap_or(true))
+                        .filter(|_| caps.has(cap::DELETIONS))
+                        .collect();
+                    println!("Server summary: {} files, {} tombstones (filter={:?})", files.len(), tombstones.len(), filter_norm);
+                    send_msg(send, &Msg::Summary { files, tombstones }).await?;
+                    // Built on the first push that needs it
+                    let mut locator: Option<crate::syncer::ChunkLocator> = None;
+                    // Chunk tree of the file being walked with RequestNodes
+                    let mut walk_tree: Option<(String, [u8; 32], crate::merkle::MerkleTree)> = None;
                     // control loop for this stream
                     loop {
                         match recv_msg(recv).await? {
                             Some(Msg::RequestFile { rel_path }) => {
-                                let filter_norm: Option<String> = only_file.as_ref().map(|s| normalize_rel(s));
-                                if let Some(ref f) = filter_norm { if normalize_rel(&rel_path) != *f { let _ = send_msg(send, &Msg::Done).await; continue; } }
-                                let abs = folder.join(&rel_path);
-                                let chunks = if abs.exists() { chunk_file(&abs)? } else { Vec::new() };
-                                let size = std::fs::metadata(&abs).map(|m| m.len()).unwrap_or(0);
-                                let chunk_hashes: Vec<[u8; 32]> = chunks.iter().map(|c| c.hash).collect();
-                                send_msg(send, &Msg::FileMeta { rel_path: rel_path.clone(), size, chunk_count: chunks.len() as u64, root: merkle_root_from_chunks(&chunks), chunk_hashes }).await?;
+                                let Some(rel) = peer_path(send, &rel_path).await? else { continue };
+                                if let Some(ref f) = filter_norm { if rel.as_str() != f { let _ = send_msg(send, &Msg::Done).await; continue; } }
+                                if !readable_in_share(&folder, &rel, symlinks) { let _ = send_msg(send, &Msg::Done).await; continue; }
+                                // xattrs are cheap to send; the client decides whether to apply them
+                                send_msg(send, &file_meta(&folder, &rel, chunking, caps.has(cap::META_XATTRS), true)?).await?;
+                            }
+                            Some(Msg::RequestMeta { rel_path }) => {
+                                let Some(rel) = peer_path(send, &rel_path).await? else { continue };
+                                if let Some(ref f) = filter_norm { if rel.as_str() != f { let _ = send_msg(send, &Msg::Done).await; continue; } }
+                                if !readable_in_share(&folder, &rel, symlinks) { let _ = send_msg(send, &Msg::Done).await; continue; }
+                                send_msg(send, &file_meta(&folder, &rel, chunking, caps.has(cap::META_XATTRS), false)?).await?;
+                            }
+                            Some(Msg::RequestNodes { rel_path, root, level, indices }) => {
+                                let Some(rel) = peer_path(send, &rel_path).await? else { continue };
+                                let readable = filter_norm.as_ref().map(|f| rel.as_str() == f).unwrap_or(true) && readable_in_share(&folder, &rel, symlinks);
+                                let current = if readable { syncer::local_entry(&folder, &rel, chunking, symlinks)? } else { None };
+                                let chunks = match current {
+                                    Some((chunks, current)) if current == root => chunks,
+                                    other => { send_msg(send, &Msg::Conflict { rel_path, root: other.map(|e| e.1) }).await?; continue; }
+                                };
+                                if level == 0 {
+                                    let chunks = indices.iter().filter_map(|&i| chunks.get(i as usize).cloned()).collect();
+                                    send_msg(send, &Msg::Nodes { rel_path, level, hashes: Vec::new(), chunks }).await?;
+                                    continue;
+                                }
+                                // A walk asks for one level per round; build the file's tree once
+                                if walk_tree.as_ref().map(|(p, r, _)| (p, r)) != Some((&rel_path, &root)) {
+                                    walk_tree = Some((rel_path.clone(), root, crate::merkle::build_merkle(&chunks)));
+                                }
+                                let Some((_, _, tree)) = &walk_tree else { continue };
+                                let hashes = indices.iter().map(|&i| crate::merkle::node_hash(tree, level, i).unwrap_or_default()).collect();
+                                send_msg(send, &Msg::Nodes { rel_path, level, hashes, chunks: Vec::new() }).await?;
                             }
-                            Some(Msg::FileMeta { rel_path, size, chunk_count: _chunk_count, root, chunk_hashes }) => {
-                                let filter_norm: Option<String> = only_file.as_ref().map(|s| normalize_rel(s));
-                                if is_internal_rel(&rel_path) { let _ = send_msg(send, &Msg::Done).await; continue; }
-                                if let Some(ref f) = filter_norm { if normalize_rel(&rel_path) != *f { let _ = send_msg(send, &Msg::Done).await; continue; } }
-                                let abs = folder.join(&rel_path);
-                                let local_chunks: Vec<ChunkInfo> = if abs.exists() { chunk_file(&abs)? } else { Vec::new() };
-                                let need = crate::syncer::diff_needed_indices(&local_chunks, &chunk_hashes);
-                                // Seed staging with existing destination before applying deltas
-                                let _ = crate::syncer::seed_staging_from_dest(&folder, &rel_path);
-                                send_msg(send, &Msg::RequestChunks { rel_path: rel_path.clone(), indices: need.clone() }).await?;
+                            Some(Msg::FileMeta { rel_path, size, chunk_count: _chunk_count, root, chunks, base, attrs }) => {
+                                if !may_write(send, access, &rel_path).await? { continue; }
+                                let Some(rel) = peer_path(send, &rel_path).await? else { continue };
+                                if is_internal_rel(&rel) || is_ignored(&folder, &rel) { let _ = send_msg(send, &Msg::Done).await; continue; }
+                                if let Some(ref f) = filter_norm { if rel.as_str() != f { let _ = send_msg(send, &Msg::Done).await; continue; } }
+                                if !writable_in_share(&folder, &rel) { let _ = send_msg(send, &Msg::Done).await; continue; }
+                                let abs = rel.to_path(&folder);
+                                let (local_chunks, current) = match syncer::local_entry(&folder, &rel, chunking, symlinks)? {
+                                    Some((c, r)) => (c, Some(r)),
+                                    None => (Vec::new(), None),
+                                };
+                                // Never clobber a copy the pusher has not seen: it must resolve the conflict first
+                                if current == Some(root) { send_msg(send, &Msg::Ack { rel_path, ok: true }).await?; continue; }
+                                if current != base {
+                                    println!("Push conflict for {}: peer's base does not match our copy", rel_path);
+                                    send_msg(send, &Msg::Conflict { rel_path, root: current }).await?;
+                                    continue;
+                                }
+                                // Seed staging with the chunks we already have anywhere in the share before applying deltas
+                                if locator.is_none() {
+                                    let files = crate::syncer::all_summaries(&folder, chunking, symlinks)?;
+                                    locator = Some(crate::syncer::ChunkLocator::build(&folder, chunking, files.iter().map(|(s, c)| (s.rel_path.as_str(), c.as_slice()))));
+                                }
+                                let seeded = crate::syncer::seed_staging(&folder, &rel, &abs, &local_chunks, &chunks, locator.as_ref()).unwrap_or_default();
+                                let need: Vec<u64> = chunks.iter().map(|c| c.index).filter(|i| !seeded.contains(i)).collect();
+                                send_msg(send, &Msg::RequestChunks { rel_path: rel_path.clone(), ranges: crate::syncer::ranges_for(&chunks, &need), compress: caps.has(cap::COMPRESS_ZSTD) }).await?;
                                 // Receive the chunk data then Done
                                 loop {
                                     match recv_msg(recv).await? {
-                                        Some(Msg::ChunkData { rel_path: rp, index, data }) if rp == rel_path => {
-                                            let _ = crate::syncer::apply_chunk_staging(&folder, &rel_path, index, &data)?;
+                                        Some(Msg::ChunkData { rel_path: rp, offset, data, compressed, .. }) if rp == rel_path => {
+                                            // A bad payload just leaves a hole; the Merkle check below refuses the file
+                                            let wire = data.len() as u64;
+                                            let Ok(data) = crate::protocol::chunk_payload(data, compressed) else { continue };
+                                            crate::status::chunk_bytes(data.len() as u64, wire);
+                                            let _ = crate::syncer::apply_chunk_staging(&folder, &rel, offset, &data)?;
                                         }
                                         Some(Msg::Done) => {
-                                            let _ = crate::syncer::truncate_staging_to_size(&folder, &rel_path, size);
-                                            let staged = crate::syncer::staging_path(&folder, &rel_path);
+                                            let _ = crate::syncer::truncate_staging_to_size(&folder, &rel, size);
+                                            let staged = crate::syncer::staging_path(&folder, &rel);
                                             let mut ok = false;
-                                            if let Ok(chunks_now) = crate::chunk::chunk_file(&staged) {
-                                                let tree = crate::merkle::build_merkle(&chunks_now);
-                                                let root_now = crate::merkle::root_hash(&tree);
-                                                if root_now == root { ok = true; }
+                                            if let Ok(chunks_now) = chunk_file(&staged, chunking) {
+                                                if merkle_root_from_chunks(&chunks_now) == root { ok = true; }
                                             }
-                                            if ok { let _ = crate::syncer::finalize_staging(&folder, &rel_path); }
+                                            if ok { ok = crate::syncer::finalize_staging(&folder, &rel).is_ok(); }
+                                            if ok { syncer::apply_attrs(&abs, &attrs, apply_xattrs); }
                                             else { println!("Push verify failed for {}", rel_path); }
+                                            if ok {
+                                                // A pushed file resurrects a deleted path
+                                                let mut ts = TombstoneStore::load(&folder)?;
+                                                if ts.entries.remove(rel.as_str()).is_some() { ts.save()?; }
+                                            }
+                                            send_msg(send, &Msg::Ack { rel_path: rel_path.clone(), ok }).await?;
                                             break;
                                         }
                                         other => { if other.is_none() { break; } }
                                     }
                                 }
                             }
-                            Some(Msg::RequestChunks { rel_path, indices }) => {
-                                let abs = folder.join(&rel_path);
-                                for idx in indices {
-                                    let data = read_chunk(&abs, idx).unwrap_or_default();
-                                    send_msg(send, &Msg::ChunkData { rel_path: rel_path.clone(), index: idx, data }).await?;
+                            Some(Msg::Delete { tombstone }) => {
+                                let rel_path = tombstone.rel_path.clone();
+                                if !may_write(send, access, &rel_path).await? { continue; }
+                                let Some(rel) = peer_path(send, &rel_path).await? else { continue };
+                                if is_internal_rel(&rel) || is_ignored(&folder, &rel) { let _ = send_msg(send, &Msg::Ack { rel_path, ok: false }).await; continue; }
+                                if let Some(ref f) = filter_norm { if rel.as_str() != f { let _ = send_msg(send, &Msg::Ack { rel_path, ok: false }).await; continue; } }
+                                if !writable_in_share(&folder, &rel) { let _ = send_msg(send, &Msg::Ack { rel_path, ok: false }).await; continue; }
+                                let current = syncer::local_entry(&folder, &rel, chunking, symlinks)?.map(|e| e.1);
+                                // Only delete the exact version the peer saw; a newer edit here wins
+                                if current.is_some() && current != Some(tombstone.root) {
+                                    send_msg(send, &Msg::Conflict { rel_path, root: current }).await?;
+                                    continue;
+                                }
+                                // Directories only go once empty; anything still inside was not part of the peer's deletion
+                                let ok = if current == Some(dir_root()) {
+                                    syncer::remove_empty_dir(&folder, &rel).unwrap_or(false)
+                                } else {
+                                    move_to_trash(&folder, &rel).is_ok()
+                                };
+                                if ok {
+                                    println!("Deleted by peer (moved to trash): {}", rel);
+                                    let mut ts = TombstoneStore::load(&folder)?;
+                                    ts.entries.insert(rel.to_string(), Tombstone { rel_path: rel.to_string(), ..tombstone });
+                                    ts.save()?;
+                                }
+                                send_msg(send, &Msg::Ack { rel_path, ok }).await?;
+                            }
+                            Some(Msg::Rename { from, to, root }) => {
+                                if !may_write(send, access, &from).await? { continue; }
+                                let Some(from_rel) = peer_path(send, &from).await? else { continue };
+                                let Some(to_rel) = peer_path(send, &to).await? else { continue };
+                                let bad = |p: &RelPath| is_internal_rel(p) || is_ignored(&folder, p) || !writable_in_share(&folder, p) || filter_norm.as_ref().map(|f| p.as_str() != f).unwrap_or(false);
+                                if bad(&from_rel) || bad(&to_rel) { send_msg(send, &Msg::Ack { rel_path: to, ok: false }).await?; continue; }
+                                let (abs_from, abs_to) = (from_rel.to_path(&folder), to_rel.to_path(&folder));
+                                let current = syncer::local_entry(&folder, &from_rel, chunking, symlinks)?.map(|e| e.1);
+                                if current != Some(root) || std::fs::symlink_metadata(&abs_to).is_ok() {
+                                    send_msg(send, &Msg::Conflict { rel_path: from, root: current }).await?;
+                                    continue;
+                                }
+                                if let Some(p) = abs_to.parent() { std::fs::create_dir_all(p)?; }
+                                let ok = std::fs::rename(&abs_from, &abs_to).is_ok();
+                                if ok {
+                                    println!("Renamed by peer: {} -> {}", from_rel, to_rel);
+                                    index::rename_entry(&folder, &from_rel, &to_rel);
+                                    // Other clients see the old path as deleted, the new one as a copy they already have
+                                    let mut ts = TombstoneStore::load(&folder)?;
+                                    ts.entries.remove(to_rel.as_str());
+                                    ts.entries.insert(from_rel.to_string(), Tombstone { rel_path: from_rel.to_string(), root, deleted_at: chrono::Utc::now().timestamp() });
+                                    ts.save()?;
+                                }
+                                send_msg(send, &Msg::Ack { rel_path: to, ok }).await?;
+                            }
+                            Some(Msg::MakeDir { rel_path }) => {
+                                if !may_write(send, access, &rel_path).await? { continue; }
+                                let Some(rel) = peer_path(send, &rel_path).await? else { continue };
+                                let refuse = is_internal_rel(&rel) || is_ignored_as(&folder, &rel, true) || !writable_in_share(&folder, &rel)
+                                    || filter_norm.as_ref().map(|f| rel.as_str() != f).unwrap_or(false);
+                                let abs = rel.to_path(&folder);
+                                let ok = !refuse && match std::fs::symlink_metadata(&abs) {
+                                    Ok(m) => m.is_dir(),
+                                    Err(_) => std::fs::create_dir_all(&abs).is_ok(),
+                                };
+                                if ok {
+                                    let mut ts = TombstoneStore::load(&folder)?;
+                                    if ts.entries.remove(rel.as_str()).is_some() { ts.save()?; }
+                                }
+                                send_msg(send, &Msg::Ack { rel_path, ok }).await?;
+                            }
+                            Some(Msg::Symlink { rel_path, target, base }) => {
+                                if !may_write(send, access, &rel_path).await? { continue; }
+                                let Some(rel) = peer_path(send, &rel_path).await? else { continue };
+                                // Only stored as a link; with any other policy we would not sync it back as one
+                                let refuse = symlinks != SymlinkPolicy::Link || is_internal_rel(&rel) || is_ignored(&folder, &rel)
+                                    || !writable_in_share(&folder, &rel)
+                                    || filter_norm.as_ref().map(|f| rel.as_str() != f).unwrap_or(false);
+                                if refuse { send_msg(send, &Msg::Ack { rel_path, ok: false }).await?; continue; }
+                                let current = syncer::local_entry(&folder, &rel, chunking, symlinks)?.map(|e| e.1);
+                                if current == Some(crate::chunk::link_root(&target)) { send_msg(send, &Msg::Ack { rel_path, ok: true }).await?; continue; }
+                                if current != base {
+                                    send_msg(send, &Msg::Conflict { rel_path, root: current }).await?;
+                                    continue;
+                                }
+                                let ok = syncer::make_symlink(&folder, &rel, &target).is_ok();
+                                if ok {
+                                    println!("Symlink from peer: {} -> {}", rel, target);
+                                    let mut ts = TombstoneStore::load(&folder)?;
+                                    if ts.entries.remove(rel.as_str()).is_some() { ts.save()?; }
+                                }
+                                send_msg(send, &Msg::Ack { rel_path, ok }).await?;
+                            }
+                            Some(Msg::RequestBatch { files, compress }) => {
+                                // Paths we will not serve are left out; the client falls back to asking one by one.
+                                // An invalid path gets an InvalidPath error in place of its FileMeta.
+                                for item in files {
+                                    let Some(rel) = peer_path(send, &item.rel_path).await? else { continue };
+                                    if is_internal_rel(&rel) { continue; }
+                                    if let Some(ref f) = filter_norm { if rel.as_str() != f { continue; } }
+                                    if !readable_in_share(&folder, &rel, symlinks) { continue; }
+                                    let abs = rel.to_path(&folder);
+                                    let Ok(meta) = std::fs::metadata(&abs) else { continue };
+                                    if !meta.is_file() { continue; }
+                                    let Ok((chunks, root)) = index::chunk_file_cached(&folder, &rel, chunking) else { continue };
+                                    let attrs = syncer::read_attrs(&abs, caps.has(cap::META_XATTRS)).unwrap_or_default();
+                                    let ranges: Vec<ChunkRange> = chunks.iter().map(ChunkRange::from).collect();
+                                    let with_data = meta.len() <= BATCH_FILE_MAX && !item.known.contains(&root);
+                                    send_msg(send, &Msg::FileMeta { rel_path: item.rel_path.clone(), size: meta.len(), chunk_count: chunks.len() as u64, root, chunks, base: None, attrs }).await?;
+                                    if with_data { send_chunks(send, &item.rel_path, &abs, &ranges, compress).await?; }
                                 }
                                 send_msg(send, &Msg::Done).await?;
                             }
+                            Some(Msg::RequestChunks { rel_path, ranges, compress }) => {
+                                let Some(rel) = peer_path(send, &rel_path).await? else { continue };
+                                if !readable_in_share(&folder, &rel, symlinks) { send_msg(send, &Msg::Done).await?; continue; }
+                                let abs = rel.to_path(&folder);
+                                send_chunks(send, &rel_path, &abs, &ranges, compress).await?;
+                                send_msg(send, &Msg::Done).await?;
+                            }
                             Some(Msg::Done) | None => break,
                             _ => {}
                         }
                     }
+                    if let Err(e) = index::flush(&folder) { eprintln!("hash index flush failed: {e:?}"); }
                 }
             }
-            Msg::RequestChunks { rel_path, indices } => {
+            Msg::RequestChunks { rel_path, ranges, compress } => {
                 // Chunk-only stream
-                let abs = folder.join(&rel_path);
-                for idx in indices {
-                    let data = read_chunk(&abs, idx).unwrap_or_default();
-                    send_msg(send, &Msg::ChunkData { rel_path: rel_path.clone(), index: idx, data }).await?;
-                }
+                let Share { path: folder, symlinks, .. } = match current { Some(s) => s.share, None => shares.resolve("", peer)?.0 };
+                let Some(rel) = peer_path(send, &rel_path).await? else { return Ok(()) };
+                if !readable_in_share(&folder, &rel, symlinks) { send_msg(send, &Msg::Done).await?; return Ok(()); }
+                let abs = rel.to_path(&folder);
+                send_chunks(send, &rel_path, &abs, &ranges, compress).await?;
                 send_msg(send, &Msg::Done).await?;
             }
             // For completeness, allow RequestFile/FileMeta without Version on a dedicated stream
             Msg::RequestFile { rel_path } => {
+                let Share { path: folder, file: only_file, symlinks, .. } = match current { Some(s) => s.share, None => shares.resolve("", peer)?.0 };
                 let filter_norm: Option<String> = only_file.as_ref().map(|s| normalize_rel(s));
-                if is_internal_rel(&rel_path) { let _ = send_msg(send, &Msg::Done).await; return Ok(()); }
-                if let Some(ref f) = filter_norm { if normalize_rel(&rel_path) != *f { let _ = send_msg(send, &Msg::Done).await; return Ok(()); } }
-                let abs = folder.join(&rel_path);
-                let chunks = if abs.exists() { chunk_file(&abs)? } else { Vec::new() };
+                let Some(rel) = peer_path(send, &rel_path).await? else { return Ok(()) };
+                if is_internal_rel(&rel) { let _ = send_msg(send, &Msg::Done).await; return Ok(()); }
+                if let Some(ref f) = filter_norm { if rel.as_str() != f { let _ = send_msg(send, &Msg::Done).await; return Ok(()); } }
+                if !readable_in_share(&folder, &rel, symlinks) { let _ = send_msg(send, &Msg::Done).await; return Ok(()); }
+                // No handshake on this stream, so only fixed chunking is meaningful here
+                let abs = rel.to_path(&folder);
+                let (chunks, root) = if abs.exists() { index::chunk_file_cached(&folder, &rel, Chunking::Fixed)? } else { (Vec::new(), [0u8; 32]) };
                 let size = std::fs::metadata(&abs).map(|m| m.len()).unwrap_or(0);
-                let chunk_hashes: Vec<[u8; 32]> = chunks.iter().map(|c| c.hash).collect();
-                send_msg(send, &Msg::FileMeta { rel_path: rel_path.clone(), size, chunk_count: chunks.len() as u64, root: merkle_root_from_chunks(&chunks), chunk_hashes }).await?;
+                let attrs = syncer::read_attrs(&abs, true).unwrap_or_default();
+                send_msg(send, &Msg::FileMeta { rel_path: rel_path.clone(), size, chunk_count: chunks.len() as u64, root, chunks, base: None, attrs }).await?;
+            }
+            Msg::TreeRequest { mut dirs } => {
+                // Tree comparison; like Subscribe it follows the share this connection's Hello picked
+                let Some(Selected { share, chunking, caps, .. }) = current else {
+                    return Err(ProtocolError::Malformed("TreeRequest before Hello".into()).into());
+                };
+                // A single-file share does not list the rest of its folder; the client falls back to a full pass
+                if share.file.is_some() {
+                    send_msg(send, &Msg::Error { code: ErrorCode::AccessDenied, message: "this share serves a single file; no tree comparison".into() }).await?;
+                    return Ok(());
+                }
+                // One walk answers every round of the comparison; it may hash files the index has not seen
+                let (links, dirs_too) = (caps.has(cap::SYMLINKS), caps.has(cap::DIRS));
+                let tree = tokio::task::spawn_blocking(move || syncer::share_tree(&share.path, chunking, share.symlinks, links, dirs_too)).await??;
+                loop {
+                    let nodes = dirs.into_iter().filter(|(d, h)| tree.hash(d) != Some(*h))
+                        .map(|(path, _)| TreeNode { children: tree.children(&path), path }).collect();
+                    send_msg(send, &Msg::Tree { nodes }).await?;
+                    match recv_msg(recv).await? {
+                        Some(Msg::TreeRequest { dirs: next }) => dirs = next,
+                        _ => break,
+                    }
+                }
+            }
+            Msg::Subscribe => {
+                // Notification stream; it follows the share this connection's Hello picked
+                let Some(Selected { share: Share { path: folder, file: only_file, .. }, .. }) = current else {
+                    return Err(ProtocolError::Malformed("Subscribe before Hello".into()).into());
+                };
+                let Some(feed) = feeds.get(&folder) else { return Ok(()) };
+                let mut rx = feed.subscribe();
+                let only = only_file.map(|f| normalize_rel(&f));
+                loop {
+                    match rx.recv().await {
+                        Ok(paths) => {
+                            let paths: Vec<String> = paths.into_iter().filter(|p| only.as_ref().map(|f| f == p).unwrap_or(true)).collect();
+                            if paths.is_empty() { continue; }
+                            // Fails once the client is gone
+                            if send_msg(send, &Msg::Changed { paths }).await.is_err() { break; }
+                        }
+                        // A client that falls behind misses these; its periodic full pass catches up
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
             }
             other => {
                 // ignore or unhandled on non-control stream
@@ -196,12 +568,45 @@ async fn handle_server_stream(folder: PathBuf, only_file: Option<String>, send:
     Ok(())
 }
 
+/// Per-session client knobs shared by the CLI `connect` command and the web UI.
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// Sync only a specific file (relative to folder)
+    pub only_file: Option<String>,
+    /// Move local-only files into .leafsync_trash
+    pub mirror: bool,
+    /// Concurrent download streams per file (clamped to 1-16)
+    pub streams: usize,
+    /// Files transferred at once in the pull phase (clamped to 1-16)
+    pub parallel_files: usize,
+    /// Global rate limit in Mbps
+    pub rate_mbps: Option<f64>,
+    /// Chunking scheme proposed to the server
+    pub chunking: Chunking,
+    /// Send and apply extended attributes (Unix only)
+    pub xattrs: bool,
+    /// Sync symlinks as links, follow them, or skip them
+    pub symlinks: SymlinkPolicy,
+    /// Share to sync on the server; empty picks the server's only share
+    pub share: String,
+    /// Pull only, push only, or both ways
+    pub direction: Direction,
+    /// Full pass at least this often; local changes are picked up in between as they happen
+    pub rescan_secs: u64,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self { only_file: None, mirror: false, streams: 4, parallel_files: 4, rate_mbps: None, chunking: Chunking::Fixed, xattrs: false, symlinks: SymlinkPolicy::Link, share: String::new(), direction: Direction::Both, rescan_secs: 30 }
+    }
+}
+
 #[allow(dead_code)]
 pub async fn run_client(addr: String, folder: PathBuf, accept_first: bool, fingerprint: Option<String>) -> Result<()> {
-    run_client_filtered(addr, folder, accept_first, fingerprint, None, false, 4, None).await
+    run_client_filtered(addr, folder, accept_first, fingerprint, SyncOptions::default()).await
 }
 
-pub async fn run_client_filtered(addr: String, folder: PathBuf, accept_first: bool, fingerprint: Option<String>, only_file: Option<String>, mirror: bool, streams: usize, rate_mbps: Option<f64>) -> Result<()> {
+pub async fn run_client_filtered(addr: String, folder: PathBuf, accept_first: bool, fingerprint: Option<String>, opts: SyncOptions) -> Result<()> {
     let server_addr: SocketAddr = addr.parse()?;
     // Determine expected fingerprint from CLI or trust store
     let expected = if let Some(fp) = fingerprint { Some(fp) } else { trust::get(&addr)? };
@@ -209,191 +614,784 @@ pub async fn run_client_filtered(addr: String, folder: PathBuf, accept_first: bo
     let mut endpoint = Endpoint::client("0.0.0.0:0".parse().unwrap())?;
     endpoint.set_default_client_config(client_cfg);
 
-    let connection = endpoint.connect(server_addr, "localhost")?.await?;
-    println!("Connected to server");
-    crate::status::set_active(true).await;
+    // Local changes trigger passes limited to the changed paths
+    let rescan = std::time::Duration::from_secs(opts.rescan_secs.max(1));
+    let mut watcher = match crate::watch::FolderWatcher::new(&folder) {
+        Ok(w) => Some(w),
+        Err(e) => { eprintln!("file watching unavailable ({e}); rescanning every {}s", rescan.as_secs()); None }
+    };
+
+    // The first connect fails outright (wrong address, pin mismatch); once a session has
+    // run, a dropped connection is retried until the session is stopped
+    let mut connection = endpoint.connect(server_addr, "localhost")?.await?;
+    let mut backoff = Backoff::default();
+    loop {
+        println!("Connected to server");
+        crate::status::connected().await;
+        crate::status::set_active(true).await;
+        let mut err = match sync_passes(&connection, &addr, &folder, &opts, &mut watcher, rescan, &mut backoff).await {
+            // The server refused the session; retrying would not change its mind
+            Ok(()) => return Ok(()),
+            // Local failures (history, disk, a conflict copy) would only repeat after a reconnect
+            Err(e) if !connection_lost(&connection, &e) => return Err(e),
+            Err(e) => e,
+        };
+        // Passes pick up where they left off: partial downloads are in the resume store
+        connection = loop {
+            let delay = backoff.next_delay();
+            println!("Connection lost ({err}); reconnecting in {:.1}s (attempt {})", delay.as_secs_f64(), backoff.attempt);
+            crate::status::reconnecting(backoff.attempt, delay, &err.to_string()).await;
+            tokio::time::sleep(delay).await;
+            match endpoint.connect(server_addr, "localhost")?.await {
+                Ok(c) => break c,
+                Err(e) => err = e.into(),
+            }
+        };
+    }
+}
+
+/// Whether `err` ended the session because the connection went away, rather than
+/// something local that a reconnect would not fix.
+fn connection_lost(connection: &quinn::Connection, err: &anyhow::Error) -> bool {
+    connection.close_reason().is_some() || err.chain().any(|e| e.is::<quinn::ConnectionError>())
+}
 
+/// Sync passes over one connection until it fails (Err) or the server refuses the
+/// session (Ok). Each pass opens a fresh control stream.
+async fn sync_passes(connection: &quinn::Connection, addr: &str, folder: &Path, opts: &SyncOptions, watcher: &mut Option<crate::watch::FolderWatcher>, rescan: std::time::Duration, backoff: &mut Backoff) -> Result<()> {
+    let SyncOptions { only_file, mirror, streams, parallel_files, rate_mbps, chunking, xattrs, symlinks, share, direction, .. } = opts.clone();
+    let (addr, folder) = (addr.to_string(), folder.to_path_buf());
     // Normalize filter for comparison
     let filter_norm: Option<String> = only_file.map(|s| normalize_rel(&s));
-    
-    // Helper: skip internal/ignored patterns for deletion
-    fn is_ignored_rel(rel: &str) -> bool { is_internal_rel(rel) }
-    
+    // None for a full pass, as the first one on every connection is
+    let mut scope: Option<HashSet<String>> = None;
+    let mut subscribed = false;
+
     loop {
         // Open a fresh control stream for this sync pass
         let (mut send, mut recv) = connection.open_bi().await?;
         // version negotiation: send first, then expect server's version
-        send_msg(&mut send, &Msg::Version { major: 1, minor: 0 }).await?;
-        match recv_msg(&mut recv).await? {
-            Some(Msg::Version { major, .. }) if major == 1 => {}
+        send_msg(&mut send, &Msg::Version { major: PROTOCOL_MAJOR, minor: PROTOCOL_MINOR, capabilities: Capabilities::ours().to_vec() }).await?;
+        let caps = match recv_msg(&mut recv).await? {
+            Some(Msg::Version { major, minor, capabilities }) => match Capabilities::negotiate(major, &capabilities) {
+                Ok(caps) => caps,
+                Err(e) => { println!("Server speaks {}.{}: {}", major, minor, e); break; }
+            },
+            Some(Msg::Error { code, message }) => { println!("Server refused the session ({:?}): {}", code, message); break; }
             other => { println!("Expected Version from server, got {:?}", other); break; }
-        }
-        // hello + get summary
-        send_msg(&mut send, &Msg::Hello { folder: folder.to_string_lossy().to_string() }).await?;
-        let summary = match recv_msg(&mut recv).await? { Some(Msg::Summary { files }) => files, other => { println!("Expected Summary, got {:?}", other); vec![] } };
-        // Optional mirror: remove local files missing on server (move to trash)
-        if mirror {
-            use std::collections::HashSet;
-            let remote_set: HashSet<String> = summary.iter().map(|f| normalize_rel(&f.rel_path)).collect();
-            // Lightweight local listing
-            let mut local_set: HashSet<String> = HashSet::new();
-            for rel in rel_paths_in_dir(&folder)? { local_set.insert(normalize_rel(&rel.to_string_lossy())); }
-            for rel in local_set.difference(&remote_set) {
-                if let Some(ref f) = filter_norm { if rel != f { continue; } }
-                if is_ignored_rel(rel) { continue; }
-                if let Err(e) = move_to_trash(&folder, rel) { eprintln!("mirror trash failed for {}: {:?}", rel, e); }
-                else { println!("Mirrored delete (moved to trash): {}", rel); }
+        };
+        let xattrs = xattrs && caps.has(cap::META_XATTRS);
+        let (raw_before, wire_before) = crate::status::chunk_totals();
+        // hello (proposes chunking) + get summary
+        let proposed = if caps.has(cap::CHUNKING_FASTCDC) { chunking } else { Chunking::Fixed };
+        // After a Changed/watcher batch only those paths are summarized
+        let hello_scope = scope.as_ref().filter(|_| caps.has(cap::NOTIFY)).map(|s| s.iter().cloned().collect());
+        send_msg(&mut send, &Msg::Hello { share: share.clone(), chunking: proposed, direction, scope: hello_scope }).await?;
+        let (chunking, direction) = match recv_msg(&mut recv).await? {
+            Some(Msg::Hello { chunking: accepted, direction: allowed, .. }) => {
+                if accepted != proposed { println!("Server adjusted chunking to {}", accepted); }
+                if allowed != direction { println!("Server allows {} only on this share", allowed); }
+                (accepted, allowed)
+            }
+            Some(Msg::Error { code, message }) => { println!("Server refused the session ({:?}): {}", code, message); break; }
+            other => { println!("Expected Hello from server, got {:?}", other); break; }
+        };
+        // Server-side changes arrive on a stream of their own and join the watcher's batches
+        if !subscribed && caps.has(cap::NOTIFY) && direction.pulls() {
+            if let Some(w) = &watcher {
+                subscribed = true;
+                let (mut sub_send, mut sub_recv) = connection.open_bi().await?;
+                send_msg(&mut sub_send, &Msg::Subscribe).await?;
+                let inject = w.injector();
+                tokio::spawn(async move {
+                    // Dropping our half would end the subscription
+                    let _sub_send = sub_send;
+                    while let Ok(Some(Msg::Changed { paths })) = recv_msg(&mut sub_recv).await {
+                        for p in paths.iter().filter(|p| RelPath::parse(p).is_ok()) { inject(p); }
+                    }
+                });
             }
         }
-        println!("Server reported {} files", summary.len());
+        let (summary, tombstones) = match recv_msg(&mut recv).await? { Some(Msg::Summary { files, tombstones }) => (files, tombstones), other => { println!("Expected Summary, got {:?}", other); break; } };
+        let tombstones: HashMap<String, Tombstone> = tombstones.into_iter().map(|t| (normalize_rel(&t.rel_path), t)).collect();
+        println!("Server reported {} files, {} deletions", summary.len(), tombstones.len());
 
+        // Last roots both sides agreed on decide who changed what since the previous pass
+        let mut history = SyncHistory::load(&addr, &folder)?;
+        let mut remote_roots: HashMap<String, [u8; 32]> = HashMap::new();
+        let remote_paths: HashSet<String> = summary.iter().map(|f| normalize_rel(&f.rel_path)).collect();
+        // Local roots and chunks, for spotting renames/copies on either side (cached, so cheap)
+        let mut local_files: HashMap<String, ([u8; 32], Vec<ChunkInfo>)> = match (&filter_norm, &scope) {
+            (Some(_), _) => HashMap::new(),
+            (None, Some(s)) => syncer::summaries_for(&folder, s, chunking, symlinks).into_iter().map(|(s, c)| (normalize_rel(&s.rel_path), (s.root, c))).collect(),
+            (None, None) => syncer::all_summaries(&folder, chunking, symlinks)?.into_iter().map(|(s, c)| (normalize_rel(&s.rel_path), (s.root, c))).collect(),
+        };
+        // Any chunk already on disk (another file, the trash) is copied rather than downloaded
+        let mut locator = syncer::ChunkLocator::build(&folder, chunking, local_files.iter().map(|(k, (_, c))| (k.as_str(), c.as_slice())));
+
+        // Directories are settled after the files in them (see below)
+        let mut remote_dirs: Vec<FileSummary> = Vec::new();
+        // Files in flight, and one rate limit across all of their streams
+        let mut pulls: tokio::task::JoinSet<Result<Option<Pulled>>> = tokio::task::JoinSet::new();
+        let n_files = parallel_files.clamp(1, 16);
+        let limiter = Arc::new(tokio::sync::Mutex::new(RateLimiter::new(rate_mbps.map(|mb| (mb * 1024.0 * 1024.0) as u64))));
+        // Small files are asked for in batches, fetched when the loop reaches their first file
+        let mut batches: Vec<Option<Vec<String>>> = Vec::new();
+        let mut batch_of: HashMap<String, usize> = HashMap::new();
+        // Real roots in the summary settle most files on the spot; only pulls need a FileMeta
+        let roots_known = caps.has(cap::SUMMARY_ROOTS);
+        let pulling = |local: Option<[u8; 32]>, remote: [u8; 32], base: Option<[u8; 32]>| matches!(history::decide(local, Some(remote), base), Decision::Pull | Decision::Conflict);
+        // A push-only session pulls nothing, so there is nothing to batch
+        if caps.has(cap::BATCH) && filter_norm.is_none() && direction.pulls() {
+            let mut bytes = 0;
+            for f in summary.iter().filter(|f| f.kind == EntryKind::File && f.size <= BATCH_FILE_MAX) {
+                let key = normalize_rel(&f.rel_path);
+                if let Some(ref s) = scope { if !s.contains(&key) { continue; } }
+                if roots_known && !pulling(local_files.get(&key).map(|(r, _)| *r), f.root, history.base(&key)) { continue; }
+                if is_ignored(&folder, &f.rel_path) || RelPath::parse(&f.rel_path).is_err() { continue; }
+                if !matches!(batches.last(), Some(Some(b)) if b.len() < BATCH_MAX_FILES && bytes + f.size <= BATCH_MAX_BYTES) {
+                    batches.push(Some(Vec::new()));
+                    bytes = 0;
+                }
+                bytes += f.size;
+                batch_of.insert(f.rel_path.clone(), batches.len() - 1);
+                if let Some(Some(b)) = batches.last_mut() { b.push(f.rel_path.clone()); }
+            }
+        }
+        let mut prefetched: HashMap<String, Prefetched> = HashMap::new();
         // for each remote file, compare and request missing
         for remote in summary {
         if let Some(ref f) = filter_norm { if &normalize_rel(&remote.rel_path) != f { continue; } }
+        if let Some(ref s) = scope { if !s.contains(&normalize_rel(&remote.rel_path)) { continue; } }
+        // Never pull something our own .leafsyncignore excludes
+        if is_ignored_as(&folder, &remote.rel_path, remote.kind == EntryKind::Dir) { continue; }
+        // A server has no business naming paths outside the folder
+        let rel = match RelPath::parse(&remote.rel_path) {
+            Ok(rel) => rel,
+            Err(e) => { println!("Skipping {:?}: {}", remote.rel_path, e); continue; }
+        };
+        // Nor write through a local symlink that leads out of the folder
+        if !writable_in_share(&folder, &rel) { println!("Skipping {}: path leaves the local folder", remote.rel_path); continue; }
+        match remote.kind {
+            EntryKind::Dir => { remote_dirs.push(remote); continue; }
+            EntryKind::Symlink(_) if symlinks != SymlinkPolicy::Link => continue,
+            _ => {}
+        }
+        let (local_chunks, local_root) = match syncer::local_entry(&folder, &rel, chunking, symlinks)? {
+            Some((c, r)) => (c, Some(r)),
+            None => (Vec::new(), None),
+        };
+        let settled = roots_known && remote.kind == EntryKind::File && !pulling(local_root, remote.root, history.base(&normalize_rel(&remote.rel_path)));
         println!("Syncing {} ({} chunks)", remote.rel_path, remote.chunk_count);
-    crate::status::start_file(&remote.rel_path, remote.size).await;
-        send_msg(&mut send, &Msg::RequestFile { rel_path: remote.rel_path.clone() }).await?;
-            let meta = match recv_msg(&mut recv).await? {
-            Some(Msg::FileMeta { rel_path, size, chunk_count, root, chunk_hashes }) => (rel_path, size, chunk_count, root, chunk_hashes),
-            _ => continue,
+        if let Some(paths) = batch_of.get(&remote.rel_path).and_then(|&i| batches[i].take()) {
+            let files = paths.into_iter().map(|p| {
+                let mut known: Vec<[u8; 32]> = history.base(&normalize_rel(&p)).into_iter().collect();
+                let local = RelPath::parse(&p).ok().and_then(|r| syncer::local_entry(&folder, &r, chunking, symlinks).ok().flatten());
+                known.extend(local.map(|(_, root)| root));
+                BatchItem { rel_path: p, known }
+            }).collect();
+            // Whatever is left of the previous batch belongs to files we skipped
+            prefetched = fetch_batch(&mut send, &mut recv, files, caps.has(cap::COMPRESS_ZSTD), &limiter).await?;
+        }
+        let mut prefetched_data = Vec::new();
+        // A big file we hold some version of: learn only the chunks that differ from ours
+        let walk = caps.has(cap::MERKLE_WALK) && remote.kind == EntryKind::File && !settled
+            && local_chunks.len() >= WALK_MIN_CHUNKS && !prefetched.contains_key(&remote.rel_path);
+        let walked = if walk { fetch_walked(&mut send, &mut recv, &remote.rel_path, chunking, &local_chunks).await? } else { None };
+        // Symlinks are fully described by the summary, as are files we will not pull
+        let meta = if matches!(remote.kind, EntryKind::Symlink(_)) || settled {
+            (rel, remote.size, remote.chunk_count, remote.root, Vec::new(), remote.attrs.clone())
+        } else if let Some(p) = prefetched.remove(&remote.rel_path) {
+            prefetched_data = p.data;
+            (rel, p.size, p.chunk_count, p.root, p.chunks, p.attrs)
+        } else if let Some((size, chunk_count, root, chunks, attrs)) = walked {
+            (rel, size, chunk_count, root, chunks, attrs)
+        } else {
+            send_msg(&mut send, &Msg::RequestFile { rel_path: remote.rel_path.clone() }).await?;
+            match recv_msg(&mut recv).await? {
+                Some(Msg::FileMeta { size, chunk_count, root, chunks, attrs, .. }) => (rel, size, chunk_count, root, chunks, attrs),
+                Some(Msg::Error { code, message }) => { println!("Server rejected {}: {:?}: {}", rel, code, message); continue; }
+                _ => continue,
+            }
         };
+        let rel_key = normalize_rel(&meta.0);
+        remote_roots.insert(rel_key.clone(), meta.3);
 
-        // compute local chunk hashes
-    let abs_local = folder.join(&meta.0);
-        let local_chunks: Vec<ChunkInfo> = if abs_local.exists() { chunk_file(&abs_local)? } else { Vec::new() };
-    let need_base = crate::syncer::diff_needed_indices(&local_chunks, &meta.4);
+    let abs_local = meta.0.to_path(&folder);
+        let mut seed_src = abs_local.clone();
+        let mut seed_chunks = local_chunks.clone();
+        let mut conflict = false;
+        match history::decide(local_root, Some(meta.3), history.base(&rel_key)) {
+            Decision::UpToDate => { history.set(&rel_key, meta.3); println!("Up to date: {}", meta.0); continue; }
+            Decision::Push => { println!("Local changes pending push: {}", meta.0); continue; }
+            Decision::DeleteRemote if !caps.has(cap::DELETIONS) || !direction.pushes() => continue,
+            Decision::DeleteRemote => {
+                // Renamed locally? A new, never-synced local file with the same content
+                // becomes a server-side rename instead of a delete plus a full upload.
+                let renamed_to = local_files.iter()
+                    .filter(|_| caps.has(cap::RENAMES))
+                    .find(|(k, (r, _))| *r == meta.3 && !remote_paths.contains(*k) && history.base(k).is_none())
+                    .map(|(k, _)| k.clone());
+                if let Some(to) = renamed_to {
+                    send_msg(&mut send, &Msg::Rename { from: meta.0.to_string(), to: to.clone(), root: meta.3 }).await?;
+                    match recv_msg(&mut recv).await? {
+                        Some(Msg::Ack { ok: true, .. }) => {
+                            history.remove(&rel_key);
+                            history.set(&to, meta.3);
+                            remote_roots.insert(to.clone(), meta.3);
+                            println!("Renamed on server: {} -> {}", meta.0, to);
+                            crate::status::file_done(&meta.0, true, "renamed_remote").await;
+                            continue;
+                        }
+                        other => println!("Server refused rename {} -> {}: {:?}", meta.0, to, other),
+                    }
+                }
+                // We deleted it and the server still has the version we last synced
+                let tombstone = Tombstone { rel_path: rel_key.clone(), root: meta.3, deleted_at: chrono::Utc::now().timestamp() };
+                send_msg(&mut send, &Msg::Delete { tombstone }).await?;
+                match recv_msg(&mut recv).await? {
+                    Some(Msg::Ack { ok: true, .. }) => {
+                        history.remove(&rel_key);
+                        println!("Deleted on server (moved to trash): {}", meta.0);
+                        crate::status::file_done(&meta.0, true, "deleted_remote").await;
+                    }
+                    other => println!("Server kept {}: {:?}", meta.0, other),
+                }
+                continue;
+            }
+            Decision::DeleteLocal => continue,
+            Decision::Pull | Decision::Conflict if !direction.pulls() => { println!("Not pulling {} (push only)", meta.0); continue; }
+            Decision::Pull => {}
+            Decision::Conflict => {
+                // Both sides edited: keep ours next to the file, then take the remote version
+                let keep = history::conflict_name(&rel_key, &identity::device_name());
+                std::fs::rename(&abs_local, folder.join(&keep))?;
+                println!("Conflict on {}: kept local copy as {}", meta.0, keep);
+                crate::status::file_done(&meta.0, false, &format!("conflict: kept {}", keep)).await;
+                seed_src = folder.join(&keep);
+                conflict = true;
+            }
+        }
+        if let EntryKind::Symlink(target) = &remote.kind {
+            match syncer::make_symlink(&folder, &meta.0, target) {
+                Ok(()) => {
+                    history.set(&rel_key, meta.3);
+                    println!("Linked {} -> {}", meta.0, target);
+                    crate::status::file_done(&meta.0, true, "symlink").await;
+                }
+                Err(e) => {
+                    eprintln!("creating symlink {} failed: {:?}", meta.0, e);
+                    crate::status::file_done(&meta.0, false, "symlink_failed").await;
+                }
+            }
+            continue;
+        }
+        // Renamed or copied on the server: reuse a local file with the same content.
+        // Move it if the server no longer has the old path (and we had synced it), else copy.
+        if local_root.is_none() {
+            let same: Vec<String> = local_files.iter().filter(|(k, (r, _))| *r == meta.3 && **k != rel_key).map(|(k, _)| k.clone()).collect();
+            let movable = same.iter().find(|k| !remote_paths.contains(*k) && history.base(k) == Some(meta.3)).cloned();
+            if let Some(src) = movable.clone().or_else(|| same.first().cloned()) {
+                let (from, to) = (folder.join(&src), meta.0.to_path(&folder));
+                if let Some(p) = to.parent() { std::fs::create_dir_all(p)?; }
+                let res = if movable.is_some() { std::fs::rename(&from, &to) } else {
+                    std::fs::copy(&from, &to).map(|_| syncer::apply_attrs(&to, &meta.5, xattrs))
+                };
+                match res {
+                    Ok(()) => {
+                        if movable.is_some() {
+                            history.remove(&src);
+                            if let Some(entry) = local_files.remove(&src) { local_files.insert(rel_key.clone(), entry); }
+                            index::rename_entry(&folder, &src, &rel_key);
+                        }
+                        history.set(&rel_key, meta.3);
+                        let how = if movable.is_some() { "Renamed" } else { "Copied" };
+                        println!("{} locally instead of downloading: {} -> {}", how, src, meta.0);
+                        crate::status::file_done(&meta.0, true, &format!("{} from {}", how.to_lowercase(), src)).await;
+                        continue;
+                    }
+                    Err(e) => eprintln!("local reuse of {} for {} failed: {:?}", src, meta.0, e),
+                }
+            }
+            // Otherwise start from whichever vanished/local-only file shares the most chunks
+            let best = local_files.iter()
+                .filter(|(k, _)| !remote_paths.contains(*k))
+                .map(|(k, (_, c))| (k, c, crate::syncer::shared_chunks(c, &meta.4)))
+                .filter(|(_, _, n)| *n > 0)
+                .max_by_key(|(_, _, n)| *n);
+            if let Some((k, c, n)) = best {
+                println!("Seeding {} from {} ({} of {} chunks match)", meta.0, k, n, meta.4.len());
+                seed_src = folder.join(k);
+                seed_chunks = c.clone();
+            }
+        }
+    // Chunks we already hold (in this file at other offsets, or elsewhere) go straight into staging
+    let seeded = crate::syncer::seed_staging(&folder, &meta.0, &seed_src, &seed_chunks, &meta.4, Some(&locator)).unwrap_or_default();
+    let need_base: Vec<u64> = meta.4.iter().map(|c| c.index).filter(|i| !seeded.contains(i)).collect();
     // Merge with resume store missing list if present
     let need = if let Some(mut missing) = resume::missing_indices_for(&addr, &meta.0, meta.1, meta.2, meta.3)? {
         // intersect resume missing with base diff (only request what differs)
         missing.retain(|i| need_base.contains(i));
         if missing.is_empty() { need_base } else { missing }
     } else { need_base };
-    if need.is_empty() { println!("Up to date: {}", meta.0); continue; }
+    // Chunks that came with the batch need no request of their own
+    let need: Vec<u64> = need.into_iter().filter(|i| !prefetched_data.iter().any(|(j, _, _)| j == i)).collect();
 
         println!("Requesting {} chunks for {} using {} streams", need.len(), meta.0, streams);
-        // Shared progress
-        use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
-        let bytes_received = Arc::new(AtomicU64::new(0));
-        let received_indices = Arc::new(tokio::sync::Mutex::new(Vec::<u64>::new()));
-        // Optional rate limiter shared across streams
-        let rate = rate_mbps.map(|mb| (mb * 1024.0 * 1024.0) as u64);
-        let limiter = Arc::new(tokio::sync::Mutex::new(RateLimiter::new(rate)));
-
-        // Partition indices across streams
-        let n_streams = streams.max(1).min(16);
-        let mut parts: Vec<Vec<u64>> = vec![Vec::new(); n_streams];
-        for (i, idx) in need.iter().cloned().enumerate() { parts[i % n_streams].push(idx); }
-        // Spawn tasks
-        let mut tasks = Vec::new();
-        for part in parts.into_iter().filter(|p| !p.is_empty()) {
-            let connection_c = connection.clone();
-            let rel = meta.0.clone();
-            let folder_c = folder.clone();
-            let bytes_c = bytes_received.clone();
-            let recv_idx = received_indices.clone();
-            let limiter_c = limiter.clone();
-            tasks.push(tokio::spawn(async move {
-                if let Ok((mut s, mut r)) = connection_c.open_bi().await {
-                    // request these indices
-                    let _ = send_msg(&mut s, &Msg::RequestChunks { rel_path: rel.clone(), indices: part.clone() }).await;
-                    loop {
-                        match recv_msg(&mut r).await {
-                            Ok(Some(Msg::ChunkData { rel_path: rp, index, data })) if rp == rel => {
-                                // rate limit
-                                if let Some(_) = limiter_c.lock().await.consume(data.len() as u64).await {}
-                                let _ = crate::syncer::apply_chunk_staging(&folder_c, &rel, index, &data);
-                                recv_idx.lock().await.push(index);
-                                let new = bytes_c.fetch_add(data.len() as u64, AtomicOrdering::SeqCst) + data.len() as u64;
-                                crate::status::progress(new).await;
-                            }
-                            Ok(Some(Msg::Done)) => break,
-                            Ok(None) | Err(_) => break,
-                            _ => {}
-                        }
-                    }
-                }
-            }));
-        }
-        for t in tasks { let _ = t.await; }
-        // Upsert resume for all received indices
-        let all_recv = received_indices.lock().await.clone();
-        if !all_recv.is_empty() { let _ = resume::upsert_mark_many(&addr, &meta.0, meta.1, meta.2, meta.3, &all_recv); }
-        // Ensure staged size and finalize
-        let _ = crate::syncer::truncate_staging_to_size(&folder, &meta.0, meta.1);
-        let staged = crate::syncer::staging_path(&folder, &meta.0);
-        let mut ok = false;
-        if let Ok(chunks_now) = crate::chunk::chunk_file(&staged) {
-            let tree = crate::merkle::build_merkle(&chunks_now);
-            let root_now = crate::merkle::root_hash(&tree);
-            if root_now == meta.3 { ok = true; }
+        // Transfer, verify and finalize happen in the background, a few files at a time
+        while pulls.len() >= n_files {
+            if let Some(done) = pulls.join_next().await { settle_pull(done, &mut history, &mut locator); }
         }
-        if ok {
-            let _ = crate::syncer::finalize_staging(&folder, &meta.0);
-            let _ = resume::clear(&addr, &meta.0, meta.3);
-            crate::status::file_done(true, "finalized").await;
-        } else {
-            println!("Warning: Merkle root mismatch for {}. Kept staged file; will not finalize.", meta.0);
-            crate::status::file_done(false, "merkle_mismatch").await;
+        let (rel, size, chunk_count, root, chunks, attrs) = meta;
+        pulls.spawn(pull_file(PullJob {
+            connection: connection.clone(), addr: addr.clone(), folder: folder.clone(),
+            rel, size, chunk_count, root, chunks, attrs, prefetched: prefetched_data, need, streams, limiter: limiter.clone(),
+            compress: caps.has(cap::COMPRESS_ZSTD), chunking, xattrs, conflict, local_root,
+        }));
         }
-            println!("\nDone.");
+        // Everything pulled is in the history before directories and pushes consult it
+        while let Some(done) = pulls.join_next().await { settle_pull(done, &mut history, &mut locator); }
+        println!("\nDone.");
+
+        // Directories, deepest first: create the ones we lack, and delete on the server the
+        // ones we removed (their contents went above, so they should be empty by now)
+        remote_dirs.sort_by_key(|d| std::cmp::Reverse(d.rel_path.split('/').count()));
+        for dir in remote_dirs {
+            let Ok(rel) = RelPath::parse(&dir.rel_path) else { println!("Skipping directory {:?}: invalid path", dir.rel_path); continue };
+            let rel_key = rel.to_string();
+            remote_roots.insert(rel_key.clone(), dir.root);
+            let local = syncer::local_entry(&folder, &rel, chunking, symlinks)?.map(|e| e.1);
+            match history::decide(local, Some(dir.root), history.base(&rel_key)) {
+                Decision::UpToDate => history.set(&rel_key, dir.root),
+                Decision::Pull if local.is_none() && direction.pulls() => match std::fs::create_dir_all(rel.to_path(&folder)) {
+                    Ok(()) => { history.set(&rel_key, dir.root); println!("Created directory {}", dir.rel_path); }
+                    Err(e) => eprintln!("creating directory {} failed: {:?}", dir.rel_path, e),
+                },
+                Decision::DeleteRemote if caps.has(cap::DELETIONS) && direction.pushes() => {
+                    let tombstone = Tombstone { rel_path: rel_key.clone(), root: dir.root, deleted_at: chrono::Utc::now().timestamp() };
+                    send_msg(&mut send, &Msg::Delete { tombstone }).await?;
+                    match recv_msg(&mut recv).await? {
+                        Some(Msg::Ack { ok: true, .. }) => { history.remove(&rel_key); println!("Deleted directory on server: {}", dir.rel_path); }
+                        other => println!("Server kept directory {}: {:?}", dir.rel_path, other),
+                    }
+                }
+                Decision::Pull | Decision::Conflict if direction.pulls() => println!("Skipping directory {}: a non-directory is in the way locally", dir.rel_path),
+                Decision::Pull | Decision::Conflict => {}
+                Decision::Push | Decision::DeleteLocal | Decision::DeleteRemote => {}
+            }
         }
 
-        // Push phase: offer local files to server so it can request missing chunks
-        let locals = syncer::all_summaries(&folder)?;
+        // Push phase: offer local files the server has not seen, or that only we changed
+        let locals = match &scope {
+            Some(s) => syncer::summaries_for(&folder, s, chunking, symlinks),
+            None => syncer::all_summaries(&folder, chunking, symlinks)?,
+        };
         for (sum, chunks) in locals {
             if is_internal_rel(&sum.rel_path) { continue; }
             if let Some(ref f) = filter_norm { if &normalize_rel(&sum.rel_path) != f { continue; } }
-            // announce local file
-            let chunk_hashes: Vec<[u8;32]> = chunks.iter().map(|c| c.hash).collect();
-            send_msg(&mut send, &Msg::FileMeta { rel_path: sum.rel_path.clone(), size: sum.size, chunk_count: sum.chunk_count, root: sum.root, chunk_hashes }).await?;
-            // wait either for RequestChunks or Done/next
-            let mut to_send: Option<Vec<u64>> = None;
+            let rel_key = normalize_rel(&sum.rel_path);
+            let remote_root = remote_roots.get(&rel_key).copied();
+            // A listed file we could not get metadata for this pass is not a deletion
+            if remote_root.is_none() && remote_paths.contains(&rel_key) { continue; }
+            let base = history.base(&rel_key);
+            match history::decide(Some(sum.root), remote_root, base) {
+                Decision::Push => {}
+                Decision::DeleteLocal if caps.has(cap::DELETIONS) && direction.pulls() => {
+                    match discard_local(&folder, &sum) {
+                        Ok(true) => {
+                            history.remove(&rel_key);
+                            println!("Deleted on server, moved local copy to trash: {}", sum.rel_path);
+                        }
+                        Ok(false) => {}
+                        Err(e) => eprintln!("trash failed for {}: {:?}", sum.rel_path, e),
+                    }
+                    continue;
+                }
+                _ => continue,
+            }
+            // Never synced from our side: the server's tombstone (same content) or
+            // mirror mode mean it should go rather than be uploaded
+            if remote_root.is_none() && base.is_none() && direction.pulls() {
+                let tombstoned = tombstones.get(&rel_key).map(|t| t.root == sum.root).unwrap_or(false);
+                if tombstoned || mirror {
+                    match discard_local(&folder, &sum) {
+                        Err(e) => eprintln!("mirror trash failed for {}: {:?}", sum.rel_path, e),
+                        Ok(false) => {}
+                        Ok(true) if tombstoned => println!("Deleted on server (tombstone), moved local copy to trash: {}", sum.rel_path),
+                        Ok(true) => println!("Mirrored delete (moved to trash): {}", sum.rel_path),
+                    }
+                    continue;
+                }
+            }
+            if !direction.pushes() { continue; }
+            if sum.kind == EntryKind::Dir {
+                if !caps.has(cap::DIRS) { continue; }
+                send_msg(&mut send, &Msg::MakeDir { rel_path: sum.rel_path.clone() }).await?;
+                match recv_msg(&mut recv).await? {
+                    Some(Msg::Ack { ok: true, .. }) => history.set(&rel_key, sum.root),
+                    other => println!("Server did not create directory {}: {:?}", sum.rel_path, other),
+                }
+                continue;
+            }
+            if let EntryKind::Symlink(target) = &sum.kind {
+                if !caps.has(cap::SYMLINKS) { continue; }
+                send_msg(&mut send, &Msg::Symlink { rel_path: sum.rel_path.clone(), target: target.clone(), base: remote_root }).await?;
+                match recv_msg(&mut recv).await? {
+                    Some(Msg::Ack { ok: true, .. }) => { history.set(&rel_key, sum.root); println!("Pushed symlink {}", sum.rel_path); }
+                    other => println!("Push of symlink {} not accepted: {:?}", sum.rel_path, other),
+                }
+                continue;
+            }
+            // announce local file; the server checks it still has `remote_root`
+            let attrs = syncer::read_attrs(&folder.join(&sum.rel_path), xattrs).unwrap_or_else(|_| sum.attrs.clone());
+            send_msg(&mut send, &Msg::FileMeta { rel_path: sum.rel_path.clone(), size: sum.size, chunk_count: sum.chunk_count, root: sum.root, chunks, base: remote_root, attrs }).await?;
+            // wait either for RequestChunks, Conflict, Ack or Done/next
+            let mut to_send: Option<(Vec<ChunkRange>, bool)> = None;
             match recv_msg(&mut recv).await? {
-                Some(Msg::RequestChunks { rel_path, indices }) if rel_path == sum.rel_path => {
-                    to_send = Some(indices);
+                Some(Msg::RequestChunks { rel_path, ranges, compress }) if rel_path == sum.rel_path => {
+                    to_send = Some((ranges, compress));
                 }
+                Some(Msg::Ack { ok: true, .. }) => history.set(&rel_key, sum.root),
+                Some(Msg::Conflict { rel_path, .. }) => println!("Server copy of {} changed; will resolve on next pass", rel_path),
+                Some(Msg::Error { code: ErrorCode::AccessDenied, message }) => { println!("Not pushing: {}", message); break; }
                 Some(Msg::Done) | None => {}
                 _ => {}
             }
-            if let Some(indices) = to_send {
+            if let Some((ranges, compress)) = to_send {
                 let abs = folder.join(&sum.rel_path);
-                for idx in indices {
-                    let data = read_chunk(&abs, idx).unwrap_or_default();
-                    send_msg(&mut send, &Msg::ChunkData { rel_path: sum.rel_path.clone(), index: idx, data }).await?;
-                }
+                send_chunks(&mut send, &sum.rel_path, &abs, &ranges, compress && caps.has(cap::COMPRESS_ZSTD)).await?;
                 send_msg(&mut send, &Msg::Done).await?;
+                match recv_msg(&mut recv).await? {
+                    Some(Msg::Ack { ok: true, .. }) => { history.set(&rel_key, sum.root); println!("Pushed {}", sum.rel_path); }
+                    other => println!("Push of {} not accepted: {:?}", sum.rel_path, other),
+                }
             }
         }
+        if filter_norm.is_none() && scope.is_none() {
+            // Bases for files gone from both sides are no longer needed
+            let local_now: HashSet<String> = walk_share(&folder, symlinks)?.rel_strings().map(|p| normalize_rel(&p)).collect();
+            history.retain(|k| local_now.contains(k) || remote_paths.contains(k));
+        }
+        if let Err(e) = history.save() { eprintln!("sync history save failed: {e:?}"); }
+        let (raw, wire) = crate::status::chunk_totals();
+        if raw > raw_before { println!("Chunk data this pass: {} bytes, {} on the wire", raw - raw_before, wire - wire_before); }
 
         // signal done for this pass
         let _ = send_msg(&mut send, &Msg::Done).await;
+        if let Err(e) = index::flush(&folder) { eprintln!("hash index flush failed: {e:?}"); }
         crate::status::session_done(true, "client_done").await;
-        // short delay before next pass for near-real-time behavior
-        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+        // A pass went through, so the link is healthy again
+        backoff.reset();
+        // Next pass: as soon as local or server changes settle, or after `rescan` whatever a
+        // tree comparison turns up (a full pass without one) for anything missed
+        scope = loop {
+            let next = match watcher.as_mut() {
+                Some(w) => w.next_batch(rescan).await,
+                None => { tokio::time::sleep(rescan).await; None }
+            };
+            if next.is_some() || !caps.has(cap::TREE) || filter_norm.is_some() { break next; }
+            // Instead of a full pass, only the paths where the two share trees disagree
+            let local = syncer::share_tree(&folder, chunking, symlinks, caps.has(cap::SYMLINKS), caps.has(cap::DIRS))?;
+            match tree_diff(connection, &local).await? {
+                None => break None,
+                Some(diff) if !diff.is_empty() => break Some(diff),
+                Some(_) => println!("Share unchanged"),
+            }
+        };
+        if let Some(s) = &scope { println!("Changes in {} paths", s.len()); }
     }
+    // A pass that stopped because the connection died is worth a reconnect
+    if let Some(reason) = connection.close_reason() { return Err(reason.into()); }
     Ok(())
 }
 
-fn merkle_root_from_chunks(chunks: &[ChunkInfo]) -> [u8; 32] {
-    let tree = crate::merkle::build_merkle(chunks);
-    crate::merkle::root_hash(&tree)
+/// Everything a background pull needs once the control stream has fetched its metadata.
+struct PullJob {
+    connection: quinn::Connection,
+    addr: String,
+    folder: PathBuf,
+    rel: RelPath,
+    size: u64,
+    chunk_count: u64,
+    root: [u8; 32],
+    chunks: Vec<ChunkInfo>,
+    attrs: crate::protocol::FileAttrs,
+    /// (index, offset, bytes) of chunks that already came with a `RequestBatch`
+    prefetched: Vec<(u64, u64, Vec<u8>)>,
+    /// Chunk indices still to fetch after seeding
+    need: Vec<u64>,
+    streams: usize,
+    limiter: Arc<tokio::sync::Mutex<RateLimiter>>,
+    compress: bool,
+    chunking: Chunking,
+    xattrs: bool,
+    /// The local copy was set aside as a conflict copy, so nothing local is in the way
+    conflict: bool,
+    /// Local root when the pass decided to pull, to spot edits made during the transfer
+    local_root: Option<[u8; 32]>,
+}
+
+/// A file that was fetched, verified and moved into place.
+struct Pulled {
+    rel_key: String,
+    root: [u8; 32],
+    abs: PathBuf,
+    chunks: Vec<ChunkInfo>,
+}
+
+/// Fetch the chunks of one file over `streams` streams, check the staged result against
+/// its Merkle root and finalize it. Ok(None) when it was left staged.
+async fn pull_file(job: PullJob) -> Result<Option<Pulled>> {
+    let PullJob { connection, addr, folder, rel, size, chunk_count, root, chunks, attrs, prefetched, need, streams, limiter, compress, chunking, xattrs, conflict, local_root } = job;
+    let abs_local = rel.to_path(&folder);
+    crate::status::start_file(&rel, size).await;
+    // Shared progress
+    use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+    let bytes_received = Arc::new(AtomicU64::new(0));
+    let received_indices = Arc::new(tokio::sync::Mutex::new(Vec::<u64>::new()));
+    // Batched content is small enough to fetch again, so it skips the resume store
+    for (_, offset, data) in &prefetched {
+        let _ = crate::syncer::apply_chunk_staging(&folder, &rel, *offset, data);
+    }
+
+    // Partition indices across streams
+    let n_streams = streams.clamp(1, 16);
+    let mut parts: Vec<Vec<ChunkRange>> = vec![Vec::new(); n_streams];
+    for (i, r) in crate::syncer::ranges_for(&chunks, &need).into_iter().enumerate() { parts[i % n_streams].push(r); }
+    // Spawn tasks
+    let mut tasks = Vec::new();
+    for part in parts.into_iter().filter(|p| !p.is_empty()) {
+        let connection_c = connection.clone();
+        let rel = rel.clone();
+        let folder_c = folder.clone();
+        let bytes_c = bytes_received.clone();
+        let recv_idx = received_indices.clone();
+        let limiter_c = limiter.clone();
+        tasks.push(tokio::spawn(async move {
+            if let Ok((mut s, mut r)) = connection_c.open_bi().await {
+                // request these indices
+                let _ = send_msg(&mut s, &Msg::RequestChunks { rel_path: rel.to_string(), ranges: part.clone(), compress }).await;
+                loop {
+                    match recv_msg(&mut r).await {
+                        Ok(Some(Msg::ChunkData { rel_path: rp, index, offset, data, compressed })) if rp == rel.as_str() => {
+                            let wire = data.len() as u64;
+                            let Ok(data) = crate::protocol::chunk_payload(data, compressed) else { continue };
+                            crate::status::chunk_bytes(data.len() as u64, wire);
+                            // rate limit
+                            limiter_c.lock().await.consume(data.len() as u64).await;
+                            let _ = crate::syncer::apply_chunk_staging(&folder_c, &rel, offset, &data);
+                            recv_idx.lock().await.push(index);
+                            let new = bytes_c.fetch_add(data.len() as u64, AtomicOrdering::SeqCst) + data.len() as u64;
+                            crate::status::progress(&rel, new).await;
+                        }
+                        Ok(Some(Msg::Done)) => break,
+                        Ok(None) | Err(_) => break,
+                        _ => {}
+                    }
+                }
+            }
+        }));
+    }
+    for t in tasks { let _ = t.await; }
+    // Upsert resume for all received indices
+    let all_recv = received_indices.lock().await.clone();
+    if !all_recv.is_empty() { let _ = resume::upsert_mark_many(&addr, &rel, size, chunk_count, root, &all_recv); }
+    // Ensure staged size and finalize
+    let _ = crate::syncer::truncate_staging_to_size(&folder, &rel, size);
+    let staged = crate::syncer::staging_path(&folder, &rel);
+    let mut ok = false;
+    if let Ok(chunks_now) = chunk_file(&staged, chunking) {
+        if merkle_root_from_chunks(&chunks_now) == root { ok = true; }
+    }
+    // A local edit that landed while we were transferring wins over the pull
+    let local_now = if abs_local.exists() { Some(index::chunk_file_cached(&folder, &rel, chunking)?.1) } else { None };
+    if ok && !conflict && local_now != local_root {
+        println!("Local file changed during transfer, not finalizing: {}", rel);
+        crate::status::file_done(&rel, false, "local_changed").await;
+    } else if ok {
+        let _ = crate::syncer::finalize_staging(&folder, &rel);
+        syncer::apply_attrs(&abs_local, &attrs, xattrs);
+        let _ = resume::clear(&addr, &rel, root);
+        crate::status::file_done(&rel, true, "finalized").await;
+        return Ok(Some(Pulled { rel_key: normalize_rel(&rel), root, abs: abs_local, chunks }));
+    } else {
+        println!("Warning: Merkle root mismatch for {}. Kept staged file; will not finalize.", rel);
+        // The marks no longer describe the staged file (it was lost or moved); start over next pass
+        let _ = resume::clear(&addr, &rel, root);
+        crate::status::file_done(&rel, false, "merkle_mismatch").await;
+    }
+    Ok(None)
+}
+
+/// A small file's metadata from a `RequestBatch`, and its chunks if the server sent them.
+struct Prefetched {
+    size: u64,
+    chunk_count: u64,
+    root: [u8; 32],
+    chunks: Vec<ChunkInfo>,
+    attrs: crate::protocol::FileAttrs,
+    data: Vec<(u64, u64, Vec<u8>)>,
+}
+
+/// Send one `RequestBatch` on the control stream and collect its answer by path.
+async fn fetch_batch(send: &mut SendStream, recv: &mut RecvStream, files: Vec<BatchItem>, compress: bool, limiter: &tokio::sync::Mutex<RateLimiter>) -> Result<HashMap<String, Prefetched>> {
+    println!("Requesting {} small files in one batch", files.len());
+    send_msg(send, &Msg::RequestBatch { files, compress }).await?;
+    let mut out: HashMap<String, Prefetched> = HashMap::new();
+    loop {
+        match recv_msg(recv).await? {
+            Some(Msg::FileMeta { rel_path, size, chunk_count, root, chunks, attrs, .. }) => {
+                out.insert(rel_path, Prefetched { size, chunk_count, root, chunks, attrs, data: Vec::new() });
+            }
+            Some(Msg::ChunkData { rel_path, index, offset, data, compressed }) => {
+                let wire = data.len() as u64;
+                let Some(p) = out.get_mut(&rel_path) else { continue };
+                let Ok(data) = crate::protocol::chunk_payload(data, compressed) else { continue };
+                crate::status::chunk_bytes(data.len() as u64, wire);
+                limiter.lock().await.consume(data.len() as u64).await;
+                p.data.push((index, offset, data));
+            }
+            // One file refused; the rest of the batch still follows
+            Some(Msg::Error { code, message }) => println!("Server rejected a batched file ({:?}): {}", code, message),
+            Some(Msg::Done) | None => break,
+            other => { println!("Unexpected reply to batch: {:?}", other); break; }
+        }
+    }
+    Ok(out)
+}
+
+/// `FileMeta` for `rel_path` with its chunk list pieced together from a Merkle walk:
+/// one `RequestNodes` round per level, descending only into nodes that differ from our
+/// tree over `local`. Subtrees that match are filled in from `local`. None if the server
+/// declined or the file changed meanwhile; the caller then asks for the full list.
+async fn fetch_walked(send: &mut SendStream, recv: &mut RecvStream, rel_path: &str, chunking: Chunking, local: &[ChunkInfo]) -> Result<Option<(u64, u64, [u8; 32], Vec<ChunkInfo>, crate::protocol::FileAttrs)>> {
+    send_msg(send, &Msg::RequestMeta { rel_path: rel_path.to_string() }).await?;
+    let (size, n, root, attrs) = match recv_msg(recv).await? {
+        Some(Msg::FileMeta { size, chunk_count, root, attrs, .. }) => (size, chunk_count, root, attrs),
+        _ => return Ok(None),
+    };
+    // The count is the peer's word; check it before allocating for it
+    if n > chunking.max_count(size) || n > WALK_MAX_CHUNKS { return Ok(None); }
+    let ours = crate::merkle::build_merkle(local);
+    let mut found: Vec<Option<ChunkInfo>> = vec![None; n as usize];
+    // Start just below the root, which we already know differs
+    let mut level = crate::merkle::height(n).saturating_sub(1);
+    let mut ask: Vec<u64> = (0..crate::merkle::level_len(n, level)).collect();
+    while !ask.is_empty() {
+        send_msg(send, &Msg::RequestNodes { rel_path: rel_path.to_string(), root, level, indices: ask.clone() }).await?;
+        let (hashes, chunks) = match recv_msg(recv).await? {
+            Some(Msg::Nodes { hashes, chunks, .. }) => (hashes, chunks),
+            _ => return Ok(None),
+        };
+        if level == 0 {
+            println!("Walked the chunk tree of {}: {} of {} chunks differ", rel_path, chunks.len(), n);
+            for c in chunks.into_iter().filter(|c| c.index < n) {
+                let i = c.index as usize;
+                found[i] = Some(c);
+            }
+            break;
+        }
+        ask = crate::merkle::walk_level(&ours, local, n, level, &ask, &hashes, &mut found);
+        level -= 1;
+    }
+    // Offsets are ours for the reused chunks; lay them out again from the sizes
+    let Some(mut chunks) = found.into_iter().collect::<Option<Vec<ChunkInfo>>>() else { return Ok(None) };
+    let mut offset = 0;
+    for (i, c) in chunks.iter_mut().enumerate() {
+        c.index = i as u64;
+        c.offset = offset;
+        offset += c.size as u64;
+    }
+    if offset != size || merkle_root_from_chunks(&chunks) != root { return Ok(None); }
+    Ok(Some((size, n, root, chunks, attrs)))
+}
+
+/// Record a finished pull: its root becomes the synced base, its chunks a seeding source.
+fn settle_pull(done: Result<Result<Option<Pulled>>, tokio::task::JoinError>, history: &mut SyncHistory, locator: &mut syncer::ChunkLocator) {
+    match done {
+        Ok(Ok(Some(p))) => {
+            history.set(&p.rel_key, p.root);
+            locator.add(p.abs, &p.chunks);
+        }
+        Ok(Ok(None)) => {}
+        Ok(Err(e)) => eprintln!("pull failed: {e:?}"),
+        Err(e) => eprintln!("pull task failed: {e}"),
+    }
+}
+
+/// Compare our share tree with the server's from the top, descending only into
+/// directories whose hashes differ. Every path that differs on either side comes back;
+/// none when the shares match, which costs a single round trip. None if the server
+/// declined to compare (a single-file share); a full pass is due then.
+async fn tree_diff(connection: &quinn::Connection, local: &ShareTree) -> Result<Option<HashSet<String>>> {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let mut diff = HashSet::new();
+    let mut ask = vec![(String::new(), local.root())];
+    while !ask.is_empty() {
+        send_msg(&mut send, &Msg::TreeRequest { dirs: std::mem::take(&mut ask) }).await?;
+        let nodes = match recv_msg(&mut recv).await? {
+            Some(Msg::Tree { nodes }) => nodes,
+            Some(Msg::Error { code, message }) => { println!("Server declined the tree comparison ({:?}): {}", code, message); return Ok(None); }
+            other => anyhow::bail!("expected Tree from server, got {:?}", other),
+        };
+        for TreeNode { path, children } in nodes {
+            // Names come from the server; only paths that stay inside the share may become scope
+            if !path.is_empty() && RelPath::parse(&path).is_err() { continue; }
+            let remote = children.unwrap_or_default();
+            let ours: HashMap<String, TreeChild> = local.children(&path).unwrap_or_default().into_iter().map(|c| (c.name.clone(), c)).collect();
+            for rc in &remote {
+                let p = merkle::join(&path, &rc.name);
+                if RelPath::parse(&p).is_err() { eprintln!("Ignoring invalid path from server tree: {p:?}"); continue; }
+                match ours.get(&rc.name) {
+                    Some(lc) if lc == rc => {}
+                    Some(lc) if lc.dir && rc.dir => ask.push((p, lc.root)),
+                    lc => {
+                        // A directory only the server has is listed by descending into it
+                        if rc.dir { ask.push((p.clone(), [0u8; 32])); }
+                        if lc.is_some_and(|c| c.dir) { diff.extend(local.subtree(&p)); }
+                        diff.insert(p);
+                    }
+                }
+            }
+            for lc in ours.values().filter(|c| !remote.iter().any(|r| r.name == c.name)) {
+                let p = merkle::join(&path, &lc.name);
+                if lc.dir { diff.extend(local.subtree(&p)); }
+                diff.insert(p);
+            }
+        }
+    }
+    let _ = send.finish().await;
+    Ok(Some(diff))
 }
 
-fn move_to_trash(root: &std::path::Path, rel: &str) -> Result<()> {
-    use chrono::Local;
-    let ts = Local::now().format("%Y%m%d_%H%M%S").to_string();
-    let base = root.join(".leafsync_trash").join(ts);
-    let from = root.join(rel);
-    let to = base.join(rel);
-    if let Some(p) = to.parent() { std::fs::create_dir_all(p)?; }
-    if from.exists() {
-        std::fs::rename(&from, &to)?;
+/// Drop a local entry the other side no longer has: files and links go to the trash,
+/// directories are removed only once empty (Ok(false) otherwise).
+fn discard_local(folder: &Path, sum: &FileSummary) -> Result<bool> {
+    let rel = RelPath::parse(&sum.rel_path)?;
+    if sum.kind == EntryKind::Dir { return syncer::remove_empty_dir(folder, &rel); }
+    move_to_trash(folder, &rel)?;
+    Ok(true)
+}
+
+/// Send `ranges` of `abs` as ChunkData, compressed where that helps and `compress` allows.
+async fn send_chunks(send: &mut SendStream, rel_path: &str, abs: &Path, ranges: &[ChunkRange], compress: bool) -> Result<()> {
+    for r in ranges {
+        let data = read_range(abs, r.offset, r.size).unwrap_or_default();
+        let raw = data.len() as u64;
+        let msg = crate::protocol::chunk_data(rel_path.to_string(), r, data, compress);
+        if let Msg::ChunkData { data, .. } = &msg { crate::status::chunk_bytes(raw, data.len() as u64); }
+        send_msg(send, &msg).await?;
     }
     Ok(())
 }
 
+fn merkle_root_from_chunks(chunks: &[ChunkInfo]) -> [u8; 32] {
+    let tree = crate::merkle::build_merkle(chunks);
+    crate::merkle::root_hash(&tree)
+}
+
 async fn send_msg(send: &mut SendStream, msg: &Msg) -> Result<()> {
-    let bytes = crate::protocol::encode(msg);
+    let bytes = crate::protocol::encode(msg)?;
     let len = (bytes.len() as u32).to_be_bytes();
     send.write_all(&len).await?;
     send.write_all(&bytes).await?;
@@ -405,17 +1403,22 @@ async fn recv_msg(recv: &mut RecvStream) -> Result<Option<Msg>> {
     let mut len_buf = [0u8; 4];
     if recv.read_exact(&mut len_buf).await.is_err() { return Ok(None); }
     let len = u32::from_be_bytes(len_buf) as usize;
+    // Check before allocating: the length prefix is whatever the peer says it is
+    let max = crate::protocol::max_frame();
+    if len > max { return Err(ProtocolError::FrameTooLarge { len, max }.into()); }
     let mut buf = vec![0u8; len];
     if recv.read_exact(&mut buf).await.is_err() { return Ok(None); }
-    Ok(Some(crate::protocol::decode(&buf)))
+    Ok(Some(crate::protocol::decode(&buf)?))
 }
 
 fn make_client_config_pinned(addr: String, expected: Option<String>, accept_first: bool) -> Result<quinn::ClientConfig> {
     let roots = RootCertStore::empty();
+    // Present our own identity so servers can grant per-share access by fingerprint
+    let (cert, key, _) = identity::load_or_generate_cert()?;
     let mut crypto = RustlsClientConfig::builder()
         .with_safe_defaults()
         .with_root_certificates(roots)
-        .with_no_client_auth();
+        .with_client_auth_cert(vec![cert], key)?;
     crypto.dangerous().set_certificate_verifier(Arc::new(PinVerifier { addr, expected, accept_first }));
     let crypto = Arc::new(crypto);
     let mut cfg = quinn::ClientConfig::new(crypto);
@@ -445,7 +1448,7 @@ impl ServerCertVerifier for PinVerifier {
                 if exp.eq_ignore_ascii_case(&fp) {
                     return Ok(ServerCertVerified::assertion());
                 }
-                return Err(rustls::Error::General(format!("fingerprint mismatch: expected {}, got {}", exp, fp)));
+                Err(rustls::Error::General(format!("fingerprint mismatch: expected {}, got {}", exp, fp)))
             }
             None => {
                 if self.accept_first {
@@ -453,10 +1456,10 @@ impl ServerCertVerifier for PinVerifier {
                     let _ = trust::set(&self.addr, &fp);
                     return Ok(ServerCertVerified::assertion());
                 }
-                return Err(rustls::Error::General(format!(
+                Err(rustls::Error::General(format!(
                     "untrusted server {} with fingerprint {}. Re-run with --accept-first or --fingerprint {}",
                     self.addr, fp, fp
-                )));
+                )))
             }
         }
     }
@@ -473,15 +1476,39 @@ fn sha256_hex(bytes: &[u8]) -> String {
 fn tuned_transport() -> Arc<TransportConfig> {
     use std::time::Duration;
     let mut t = TransportConfig::default();
-    // Keep connections alive and increase idle timeout to reduce spuriously closed sessions
+    // Keep connections alive; a peer silent for 30s (several missed keep-alives) is gone,
+    // and clients reconnect rather than wait on it
     t.keep_alive_interval(Some(Duration::from_secs(5)));
-    t.max_idle_timeout(Some(quinn::IdleTimeout::from(quinn::VarInt::from_u32(120_000))));
+    t.max_idle_timeout(Some(quinn::IdleTimeout::from(quinn::VarInt::from_u32(30_000))));
     // Slightly higher flow control windows for chunk traffic
     t.receive_window(quinn::VarInt::from_u32(2 * 1024 * 1024));
     t.send_window(u64::from(quinn::VarInt::from_u32(2 * 1024 * 1024)));
     Arc::new(t)
 }
 
+/// Reconnect delays: 1s doubling up to 60s, each shortened by a random 0-50% so clients
+/// dropped together do not all come back at once.
+#[derive(Default)]
+struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    fn next_delay(&mut self) -> std::time::Duration {
+        use std::hash::{BuildHasher, Hasher};
+        let base = (1u64 << self.attempt.min(6)).min(60) as f64;
+        self.attempt += 1;
+        // RandomState is seeded randomly per instance; plenty for jitter
+        let r = std::collections::hash_map::RandomState::new().build_hasher().finish();
+        let jitter = (r % 1000) as f64 / 2000.0;
+        std::time::Duration::from_secs_f64(base * (1.0 - jitter))
+    }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
 struct RateLimiter {
     bytes_per_sec: Option<u64>,
     available: f64,
@@ -514,3 +1541,30 @@ impl RateLimiter {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_then_stays_under_a_minute() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<f64> = (0..40).map(|_| backoff.next_delay().as_secs_f64()).collect();
+        assert!(delays.iter().all(|&d| d > 0.0 && d <= 60.0), "{delays:?}");
+        // Jitter takes at most half off: 1s, 2s, 4s... until the cap
+        for (i, &d) in delays.iter().enumerate().take(6) {
+            let base = (1u64 << i) as f64;
+            assert!(d >= base / 2.0 && d <= base, "attempt {i}: {d}");
+        }
+        assert!(delays[10..].iter().all(|&d| d >= 30.0), "{delays:?}");
+    }
+
+    #[test]
+    fn backoff_reset_starts_over() {
+        let mut backoff = Backoff::default();
+        for _ in 0..10 { backoff.next_delay(); }
+        backoff.reset();
+        assert!(backoff.next_delay().as_secs_f64() <= 1.0);
+        assert_eq!(backoff.attempt, 1);
+    }
+}
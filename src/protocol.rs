@@ -1,29 +1,468 @@
+use crate::chunk::{ChunkInfo, Chunking};
+use bincode::Options;
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, fmt, sync::atomic::{AtomicI32, AtomicUsize, Ordering}};
 
+/// Wire protocol version. Peers with a different major cannot talk; within a major,
+/// optional features are agreed through capabilities instead of minor numbers.
+pub const PROTOCOL_MAJOR: u16 = 2;
+pub const PROTOCOL_MINOR: u16 = 0;
+
+/// Capability names exchanged in `Msg::Version`, as `area.feature`.
+pub mod cap {
+    pub const HASH_SHA256: &str = "hash.sha256";
+    pub const CHUNKING_FIXED: &str = "chunking.fixed";
+    pub const CHUNKING_FASTCDC: &str = "chunking.fastcdc";
+    pub const META_XATTRS: &str = "meta.xattrs";
+    pub const DELETIONS: &str = "deletions";
+    pub const RENAMES: &str = "renames";
+    pub const SYMLINKS: &str = "symlinks";
+    pub const DIRS: &str = "dirs";
+    pub const COMPRESS_ZSTD: &str = "compress.zstd";
+    /// `Subscribe`/`Changed` notifications and `Hello.scope`.
+    pub const NOTIFY: &str = "notify";
+    /// `RequestBatch` for many small files in one round trip.
+    pub const BATCH: &str = "batch";
+    /// `Summary` carries real file roots and chunk counts, not zeros and estimates.
+    pub const SUMMARY_ROOTS: &str = "summary.roots";
+    /// `TreeRequest`/`Tree`: compare whole shares by directory-level Merkle hashes.
+    pub const TREE: &str = "tree";
+    /// `RequestMeta`/`RequestNodes`: walk a file's Merkle tree instead of listing every chunk.
+    pub const MERKLE_WALK: &str = "merkle.walk";
+
+    /// Everything this build implements.
+    pub const SUPPORTED: &[&str] = &[HASH_SHA256, CHUNKING_FIXED, CHUNKING_FASTCDC, META_XATTRS, DELETIONS, RENAMES, SYMLINKS, DIRS, COMPRESS_ZSTD, NOTIFY, BATCH, SUMMARY_ROOTS, TREE, MERKLE_WALK];
+    /// Without these two peers cannot agree on a single chunk.
+    pub const REQUIRED: &[&str] = &[HASH_SHA256, CHUNKING_FIXED];
+}
+
+/// The features a session may use: what both peers advertised.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities(BTreeSet<String>);
+
+impl Capabilities {
+    pub fn ours() -> Capabilities {
+        let off = |c: &&str| *c == cap::COMPRESS_ZSTD && zstd_level() == 0;
+        Capabilities(cap::SUPPORTED.iter().filter(|c| !off(c)).map(|c| c.to_string()).collect())
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.0.contains(name)
+    }
+
+    pub fn to_vec(&self) -> Vec<String> {
+        self.0.iter().cloned().collect()
+    }
+
+    /// Intersect our capabilities with a peer's `Version`, or explain why we cannot talk.
+    pub fn negotiate(major: u16, theirs: &[String]) -> Result<Capabilities, ProtocolError> {
+        if major != PROTOCOL_MAJOR {
+            return Err(ProtocolError::Incompatible(format!("protocol {major}.x, we speak {PROTOCOL_MAJOR}.x")));
+        }
+        let ours = Capabilities::ours();
+        let shared = Capabilities(theirs.iter().filter(|c| ours.has(c)).cloned().collect());
+        let missing: Vec<&str> = cap::REQUIRED.iter().copied().filter(|c| !shared.has(c)).collect();
+        if !missing.is_empty() {
+            return Err(ProtocolError::Incompatible(format!("peer lacks required capabilities: {}", missing.join(", "))));
+        }
+        Ok(shared)
+    }
+}
+
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// zstd level for chunk payloads we send. 0 turns compression off, and we then stop
+/// advertising `compress.zstd` so the peer does not compress for us either.
+static ZSTD_LEVEL: AtomicI32 = AtomicI32::new(DEFAULT_ZSTD_LEVEL);
+
+pub fn zstd_level() -> i32 {
+    ZSTD_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn set_zstd_level(level: i32) {
+    ZSTD_LEVEL.store(level.clamp(0, 22), Ordering::Relaxed);
+}
+
+/// Largest frame either side sends or accepts unless configured otherwise. Roomy
+/// enough for the chunk list of a ~1 TiB file at 1 MiB chunks.
+pub const DEFAULT_MAX_FRAME: usize = 64 * 1024 * 1024;
+
+/// Floor for the configurable limit: one maximal FastCDC chunk plus message overhead.
+const MIN_FRAME: usize = 16 * 1024 * 1024 + 64 * 1024;
+
+static MAX_FRAME: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME);
+
+pub fn max_frame() -> usize {
+    MAX_FRAME.load(Ordering::Relaxed)
+}
+
+pub fn set_max_frame(bytes: usize) {
+    MAX_FRAME.store(bytes.max(MIN_FRAME), Ordering::Relaxed);
+}
+
+/// bincode numbers variants by position, so new ones go last; inserting one, or
+/// changing the shape of an existing one, needs a new `PROTOCOL_MAJOR`. `Version`
+/// stays first, with `major` as its first field, so any peer can read that much.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Msg {
-    Version { major: u16, minor: u16 },
-    Hello { folder: String },
-    Summary { files: Vec<FileSummary> },
+    Version { major: u16, minor: u16, capabilities: Vec<String> },
+    /// `share` names one of the server's shares; empty selects its only share. The
+    /// server echoes the chunking and direction it accepted. `scope` limits the summary
+    /// that follows to those paths (a pass after `Changed`); None asks for all of it.
+    Hello { share: String, chunking: Chunking, direction: Direction, scope: Option<Vec<String>> },
+    Summary { files: Vec<FileSummary>, tombstones: Vec<Tombstone> },
     RequestFile { rel_path: String },
-    FileMeta { rel_path: String, size: u64, chunk_count: u64, root: [u8; 32], chunk_hashes: Vec<[u8; 32]> },
-    RequestChunks { rel_path: String, indices: Vec<u64> },
-    ChunkData { rel_path: String, index: u64, data: Vec<u8> },
+    /// `base` is only set on pushes: the root the sender last synced with the receiver
+    /// (None for a new file). The receiver refuses the push if its copy has moved on.
+    FileMeta { rel_path: String, size: u64, chunk_count: u64, root: [u8; 32], chunks: Vec<ChunkInfo>, base: Option<[u8; 32]>, attrs: FileAttrs },
+    /// `compress`: the requester accepts zstd payloads (the session negotiated `compress.zstd`).
+    RequestChunks { rel_path: String, ranges: Vec<ChunkRange>, compress: bool },
+    /// `data` is zstd-compressed when `compressed` is set; see `chunk_data`/`chunk_payload`.
+    ChunkData { rel_path: String, index: u64, offset: u64, data: Vec<u8>, compressed: bool },
+    /// Ask the receiver to move its copy to trash (or remove it, for an empty directory),
+    /// if it still has `tombstone.root`.
+    Delete { tombstone: Tombstone },
+    /// Create or replace `rel_path` as a symlink to `target`; `base` as for `FileMeta` pushes.
+    Symlink { rel_path: String, target: String, base: Option<[u8; 32]> },
+    /// Create `rel_path` (and its parents) as a directory.
+    MakeDir { rel_path: String },
+    /// Move `from` to `to` on the receiver, if `from` still has `root` and `to` is free.
+    Rename { from: String, to: String, root: [u8; 32] },
+    /// Push or delete refused: the receiver's current root (None if missing) is not the sender's base.
+    Conflict { rel_path: String, root: Option<[u8; 32]> },
+    /// Outcome of a push (after verify + finalize) or a delete.
+    Ack { rel_path: String, ok: bool },
+    /// The request was rejected outright; `message` is for humans.
+    Error { code: ErrorCode, message: String },
     Done,
+    /// Opens a notification stream for the share selected by this connection's `Hello`;
+    /// the server answers with `Changed` whenever something in the share changes.
+    Subscribe,
+    /// Paths (and their parent folders) that changed on the server.
+    Changed { paths: Vec<String> },
+    /// Metadata for many files at once, answered on the control stream with a `FileMeta`
+    /// per file still present, each followed by all of its `ChunkData` unless its root is
+    /// one the requester listed as known (or the file exceeds `BATCH_FILE_MAX`), then `Done`.
+    /// An invalid path gets an `Error { code: InvalidPath }` in its place.
+    RequestBatch { files: Vec<BatchItem>, compress: bool },
+    /// Opens (and continues) a tree comparison for the share selected by this connection's
+    /// `Hello`: each directory comes with the requester's hash for it ("" is the share).
+    /// A single-file share answers with an `AccessDenied` error instead.
+    TreeRequest { dirs: Vec<(String, [u8; 32])> },
+    /// The directories from a `TreeRequest` whose hash differs here, with their children.
+    Tree { nodes: Vec<TreeNode> },
+    /// Like `RequestFile`, but the `FileMeta` answer leaves `chunks` empty.
+    RequestMeta { rel_path: String },
+    /// Nodes of a file's chunk Merkle tree at `level` (0 = leaves, 1 = their parents...),
+    /// if the file still has `root`; answered with `Nodes`, or `Conflict` if it moved on.
+    RequestNodes { rel_path: String, root: [u8; 32], level: u32, indices: Vec<u64> },
+    /// `hashes` of the requested nodes in order; at level 0 the chunks themselves instead.
+    Nodes { rel_path: String, level: u32, hashes: Vec<[u8; 32]>, chunks: Vec<ChunkInfo> },
 }
 
+/// Files up to this size are fetched through `RequestBatch`, content included.
+pub const BATCH_FILE_MAX: u64 = 128 * 1024;
+/// Bounds on one `RequestBatch`, so its answer stays a modest amount of memory.
+pub const BATCH_MAX_FILES: usize = 1024;
+pub const BATCH_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Files with at least this many chunks (32 KiB of hashes) have their chunk list walked
+/// with `RequestNodes` when we hold a version of them, rather than sent whole.
+pub const WALK_MIN_CHUNKS: usize = 1024;
+/// Most chunks a walk will take on (4 TiB of 1 MiB chunks); a larger count from a peer
+/// is not allocated for, and the file's chunk list is asked for whole instead.
+pub const WALK_MAX_CHUNKS: u64 = 1 << 22;
+
+/// One file in a `RequestBatch`. `known` lists roots the requester has no use for
+/// (its own copy, the version it last synced); their content is not sent.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchItem {
+    pub rel_path: String,
+    pub known: Vec<[u8; 32]>,
+}
+
+/// One entry of a directory in a share tree; `root` is the entry's root, or the tree
+/// hash of a subdirectory.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TreeChild {
+    pub name: String,
+    pub root: [u8; 32],
+    pub dir: bool,
+}
+
+/// A directory in a `Tree` answer; `children` is None if it is not a directory there.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TreeNode {
+    pub path: String,
+    pub children: Option<Vec<TreeChild>>,
+}
+
+/// Which way a client syncs. Pull-only never changes the server; push-only never
+/// changes the local folder.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    Pull,
+    Push,
+    #[default]
+    Both,
+}
+
+impl Direction {
+    pub fn pulls(self) -> bool {
+        self != Direction::Push
+    }
+
+    pub fn pushes(self) -> bool {
+        self != Direction::Pull
+    }
+}
+
+impl std::str::FromStr for Direction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "pull" => Ok(Direction::Pull),
+            "push" => Ok(Direction::Push),
+            "both" => Ok(Direction::Both),
+            other => anyhow::bail!("unknown direction {other:?} (expected pull, push or both)"),
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self { Direction::Pull => "pull", Direction::Push => "push", Direction::Both => "both" })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// A path that is absolute, contains `..`, or otherwise cannot stay inside the share.
+    InvalidPath,
+    /// A frame that does not decode as a `Msg`.
+    Malformed,
+    /// A frame longer than the receiver's maximum.
+    FrameTooLarge,
+    /// Different protocol major, or a required capability is missing.
+    Incompatible,
+    /// `Hello` named a share the server does not have.
+    UnknownShare,
+    /// The client's certificate is not allowed on the share, or only to read it.
+    AccessDenied,
+}
+
+/// Why a frame could not be turned into (or out of) a `Msg`.
+#[derive(Debug)]
+pub enum ProtocolError {
+    FrameTooLarge { len: usize, max: usize },
+    Malformed(String),
+    Incompatible(String),
+    UnknownShare(String),
+    AccessDenied(String),
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ProtocolError::FrameTooLarge { .. } => ErrorCode::FrameTooLarge,
+            ProtocolError::Malformed(_) => ErrorCode::Malformed,
+            ProtocolError::Incompatible(_) => ErrorCode::Incompatible,
+            ProtocolError::UnknownShare(_) => ErrorCode::UnknownShare,
+            ProtocolError::AccessDenied(_) => ErrorCode::AccessDenied,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::FrameTooLarge { len, max } => write!(f, "frame of {len} bytes exceeds the {max} byte limit"),
+            ProtocolError::Malformed(e) => write!(f, "malformed message: {e}"),
+            ProtocolError::Incompatible(e) => write!(f, "incompatible peer: {e}"),
+            ProtocolError::UnknownShare(e) | ProtocolError::AccessDenied(e) => f.write_str(e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileSummary {
     pub rel_path: String,
     pub size: u64,
+    /// Files: an estimate, and `root` all zeros, unless the session has `summary.roots`
     pub chunk_count: u64,
     pub root: [u8; 32],
+    /// mtime and mode only; xattrs travel in `FileMeta`
+    pub attrs: FileAttrs,
+    pub kind: EntryKind,
+}
+
+/// What a summary entry is. Directories and symlinks have no chunks; their `root` is
+/// `chunk::dir_root()` or `chunk::link_root(target)` so they diff like files.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub enum EntryKind {
+    #[default]
+    File,
+    Dir,
+    /// The link target, verbatim
+    Symlink(String),
+}
+
+/// File metadata applied alongside the content when a file is finalized.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct FileAttrs {
+    /// Modification time, nanoseconds since the unix epoch
+    pub mtime_ns: i64,
+    /// Unix permission bits (None from platforms without them)
+    pub mode: Option<u32>,
+    /// Extended attributes (name, value); only filled when xattr sync is enabled
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Record of a deletion: the path, the last root the deleting side knew, and when (unix secs).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tombstone {
+    pub rel_path: String,
+    pub root: [u8; 32],
+    pub deleted_at: i64,
 }
 
-pub fn encode(msg: &Msg) -> Vec<u8> {
-    bincode::serialize(msg).expect("serialize")
+/// Byte range of one chunk as laid out in the sender's copy of the file.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ChunkRange {
+    pub index: u64,
+    pub offset: u64,
+    pub size: u32,
 }
 
-pub fn decode(buf: &[u8]) -> Msg {
-    bincode::deserialize(buf).expect("deserialize")
+impl From<&ChunkInfo> for ChunkRange {
+    fn from(c: &ChunkInfo) -> Self {
+        ChunkRange { index: c.index, offset: c.offset, size: c.size }
+    }
+}
+
+/// Same wire format as `bincode::serialize`, but bounded by the frame limit so a
+/// hostile length prefix inside a message cannot make us allocate without end.
+fn wire() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes().with_limit(max_frame() as u64)
+}
+
+pub fn encode(msg: &Msg) -> Result<Vec<u8>, ProtocolError> {
+    wire().serialize(msg).map_err(|e| match *e {
+        bincode::ErrorKind::SizeLimit => ProtocolError::FrameTooLarge { len: bincode::serialized_size(msg).unwrap_or(u64::MAX) as usize, max: max_frame() },
+        other => ProtocolError::Malformed(other.to_string()),
+    })
+}
+
+pub fn decode(buf: &[u8]) -> Result<Msg, ProtocolError> {
+    if buf.len() > max_frame() { return Err(ProtocolError::FrameTooLarge { len: buf.len(), max: max_frame() }); }
+    wire().deserialize(buf).map_err(|e| match version_major(buf) {
+        // A `Version` from another major need not parse as ours; say why it failed
+        Some(major) if major != PROTOCOL_MAJOR => ProtocolError::Incompatible(format!("protocol {major}.x, we speak {PROTOCOL_MAJOR}.x")),
+        _ => ProtocolError::Malformed(e.to_string()),
+    })
+}
+
+/// The `major` of a frame that holds a `Msg::Version` (variant 0), whatever follows it.
+fn version_major(buf: &[u8]) -> Option<u16> {
+    match buf {
+        [0, 0, 0, 0, lo, hi, ..] => Some(u16::from_le_bytes([*lo, *hi])),
+        _ => None,
+    }
+}
+
+/// A `ChunkData` for one range. With `compress`, the payload is zstd-compressed at our
+/// level, but only kept that way if it actually came out smaller.
+pub fn chunk_data(rel_path: String, range: &ChunkRange, data: Vec<u8>, compress: bool) -> Msg {
+    let level = zstd_level();
+    let packed = if compress && level > 0 { zstd::bulk::compress(&data, level).ok().filter(|z| z.len() < data.len()) } else { None };
+    match packed {
+        Some(z) => Msg::ChunkData { rel_path, index: range.index, offset: range.offset, data: z, compressed: true },
+        None => Msg::ChunkData { rel_path, index: range.index, offset: range.offset, data, compressed: false },
+    }
+}
+
+/// The raw bytes of a `ChunkData` payload. Decompression is capped at the frame limit,
+/// so a tiny frame cannot expand into an arbitrarily large buffer.
+pub fn chunk_payload(data: Vec<u8>, compressed: bool) -> Result<Vec<u8>, ProtocolError> {
+    if !compressed { return Ok(data); }
+    zstd::bulk::decompress(&data, max_frame()).map_err(|e| ProtocolError::Malformed(format!("zstd: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_caps() -> Vec<String> {
+        cap::SUPPORTED.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn decode_round_trips() {
+        let buf = encode(&Msg::RequestFile { rel_path: "a/b.txt".into() }).unwrap();
+        assert!(matches!(decode(&buf), Ok(Msg::RequestFile { rel_path }) if rel_path == "a/b.txt"));
+    }
+
+    #[test]
+    fn decode_rejects_oversize_frame() {
+        let buf = vec![0u8; max_frame() + 1];
+        assert!(matches!(decode(&buf), Err(ProtocolError::FrameTooLarge { len, max }) if len == max + 1));
+    }
+
+    #[test]
+    fn decode_reports_old_major_as_incompatible() {
+        // A 1.0 `Version { major: 1, minor: 0 }`, which had no capabilities yet
+        let buf = [0u8, 0, 0, 0, 1, 0, 0, 0];
+        assert!(matches!(decode(&buf), Err(ProtocolError::Incompatible(_))));
+        let ours = encode(&Msg::Version { major: PROTOCOL_MAJOR, minor: PROTOCOL_MINOR, capabilities: Vec::new() }).unwrap();
+        assert_eq!(version_major(&ours), Some(PROTOCOL_MAJOR));
+    }
+
+    #[test]
+    fn decode_rejects_truncated_frame() {
+        let buf = encode(&Msg::Changed { paths: vec!["one".into(), "two".into()] }).unwrap();
+        assert!(matches!(decode(&buf[..buf.len() - 1]), Err(ProtocolError::Malformed(_))));
+        assert!(matches!(decode(&[]), Err(ProtocolError::Malformed(_))));
+    }
+
+    #[test]
+    fn chunk_payload_round_trips() {
+        let data = vec![7u8; 64 * 1024];
+        let Msg::ChunkData { data: wire, compressed, .. } = chunk_data("f".into(), &ChunkRange { index: 0, offset: 0, size: data.len() as u32 }, data.clone(), true) else { unreachable!() };
+        assert!(compressed && wire.len() < data.len());
+        assert_eq!(chunk_payload(wire, compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn chunk_payload_refuses_to_expand_past_max_frame() {
+        // A few KiB on the wire that would inflate beyond the frame limit
+        let bomb = zstd::bulk::compress(&vec![0u8; max_frame() + 1], 3).unwrap();
+        assert!(bomb.len() < 64 * 1024);
+        assert!(matches!(chunk_payload(bomb, true), Err(ProtocolError::Malformed(_))));
+    }
+
+    #[test]
+    fn negotiate_keeps_shared_capabilities() {
+        let mut theirs = all_caps();
+        theirs.push("from.the.future".into());
+        let shared = Capabilities::negotiate(PROTOCOL_MAJOR, &theirs).unwrap();
+        assert!(shared.has(cap::HASH_SHA256));
+        assert!(!shared.has("from.the.future"));
+    }
+
+    #[test]
+    fn negotiate_rejects_other_major() {
+        let err = Capabilities::negotiate(PROTOCOL_MAJOR + 1, &all_caps()).unwrap_err();
+        assert!(matches!(err.code(), ErrorCode::Incompatible));
+    }
+
+    #[test]
+    fn negotiate_rejects_missing_required_capability() {
+        let theirs: Vec<String> = all_caps().into_iter().filter(|c| c != cap::CHUNKING_FIXED).collect();
+        let err = Capabilities::negotiate(PROTOCOL_MAJOR, &theirs).unwrap_err();
+        assert!(matches!(err.code(), ErrorCode::Incompatible));
+        assert!(err.to_string().contains(cap::CHUNKING_FIXED), "{err}");
+    }
 }
@@ -0,0 +1,101 @@
+use anyhow::{bail, Result};
+use std::{fmt, ops::Deref, path::{Path, PathBuf}};
+
+/// A share-relative path that is safe to join onto the share root: relative,
+/// `/`-separated, and free of `..`, `.`, empty, drive-letter and NUL components.
+/// Every path a peer sends goes through `RelPath::parse` before it touches the disk.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RelPath(String);
+
+impl RelPath {
+    pub fn parse(raw: &str) -> Result<RelPath> {
+        let s = raw.replace('\\', "/");
+        if s.is_empty() { bail!("empty path"); }
+        if s.starts_with('/') { bail!("absolute path {raw:?}"); }
+        if s.contains('\0') { bail!("NUL byte in path {raw:?}"); }
+        for (i, part) in s.split('/').enumerate() {
+            match part {
+                "" | "." => bail!("empty or '.' component in {raw:?}"),
+                ".." => bail!("'..' component in {raw:?}"),
+                _ => {}
+            }
+            // `C:foo` / `C:\foo` resolve against a drive on Windows
+            let b = part.as_bytes();
+            if i == 0 && b.len() >= 2 && b[1] == b':' && b[0].is_ascii_alphabetic() { bail!("drive prefix in {raw:?}"); }
+            // Alternate data streams and reserved characters
+            if cfg!(windows) && part.contains([':', '<', '>', '"', '|', '?', '*']) { bail!("reserved character in {raw:?}"); }
+        }
+        Ok(RelPath(s))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The path under `root`. Symlinks along the way are not resolved here; see
+    /// `chunk::resolves_within` for that.
+    pub fn to_path(&self, root: &Path) -> PathBuf {
+        root.join(&self.0)
+    }
+}
+
+impl Deref for RelPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RelPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_parent_components() {
+        assert!(RelPath::parse("..").is_err());
+        assert!(RelPath::parse("../etc/passwd").is_err());
+        assert!(RelPath::parse("a/../b").is_err());
+        assert!(RelPath::parse("a/..").is_err());
+        assert!(RelPath::parse("a\\..\\b").is_err());
+    }
+
+    #[test]
+    fn rejects_absolute_and_drive_paths() {
+        assert!(RelPath::parse("/etc/passwd").is_err());
+        assert!(RelPath::parse("\\windows\\system32").is_err());
+        assert!(RelPath::parse("C:").is_err());
+        assert!(RelPath::parse("C:foo").is_err());
+        assert!(RelPath::parse("c:\\foo").is_err());
+        assert!(RelPath::parse("C:/foo").is_err());
+    }
+
+    #[test]
+    fn rejects_nul_and_empty_components() {
+        assert!(RelPath::parse("").is_err());
+        assert!(RelPath::parse("a\0b").is_err());
+        assert!(RelPath::parse(".").is_err());
+        assert!(RelPath::parse("./a").is_err());
+        assert!(RelPath::parse("a/./b").is_err());
+        assert!(RelPath::parse("a//b").is_err());
+        assert!(RelPath::parse("a/").is_err());
+    }
+
+    #[test]
+    fn accepts_nested_paths() {
+        assert_eq!(RelPath::parse("a").unwrap().as_str(), "a");
+        assert_eq!(RelPath::parse("dir/sub/file.txt").unwrap().as_str(), "dir/sub/file.txt");
+        assert_eq!(RelPath::parse("dir\\sub\\file.txt").unwrap().as_str(), "dir/sub/file.txt");
+        assert_eq!(RelPath::parse("..hidden/.dotfile").unwrap().as_str(), "..hidden/.dotfile");
+        // A colon past the first component is no drive prefix (Windows refuses it anyway)
+        #[cfg(not(windows))]
+        assert!(RelPath::parse("dir/C:").is_ok());
+        assert_eq!(RelPath::parse("a/b").unwrap().to_path(Path::new("/share")), Path::new("/share/a/b"));
+    }
+}
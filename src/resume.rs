@@ -1,6 +1,9 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, path::PathBuf};
+use std::{collections::HashMap, fs, path::PathBuf, sync::Mutex};
+
+/// Files are pulled concurrently; reads and read-modify-writes of resume.json must not interleave.
+static STORE_LOCK: Mutex<()> = Mutex::new(());
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ResumeEntry {
@@ -22,7 +25,7 @@ pub fn load_store() -> Result<ResumeStore> {
     let p = resume_path()?;
     if !p.exists() { return Ok(ResumeStore::default()); }
     let data = fs::read(&p).with_context(|| format!("read {p:?}"))?;
-    Ok(serde_json::from_slice(&data).with_context(|| "parse resume.json")?)
+    serde_json::from_slice(&data).with_context(|| "parse resume.json")
 }
 
 pub fn save_store(store: &ResumeStore) -> Result<()> {
@@ -37,6 +40,7 @@ pub fn key(addr: &str, rel_path: &str, root_hex: &str) -> String {
 
 pub fn get(addr: &str, rel_path: &str, root: &[u8; 32]) -> Result<Option<ResumeEntry>> {
     let key_s = key(addr, rel_path, &hex(root));
+    let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
     let store = load_store()?;
     Ok(store.entries.get(&key_s).cloned())
 }
@@ -47,6 +51,7 @@ pub fn upsert_mark(addr: &str, rel_path: &str, size: u64, chunk_count: u64, root
 }
 
 pub fn upsert_mark_many(addr: &str, rel_path: &str, size: u64, chunk_count: u64, root: [u8;32], indices: &[u64]) -> Result<()> {
+    let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
     let mut store = load_store()?;
     let key_s = key(addr, rel_path, &hex(&root));
     let entry = store.entries.entry(key_s).or_insert_with(|| ResumeEntry {
@@ -79,9 +84,11 @@ pub fn missing_indices_for(addr: &str, rel_path: &str, size: u64, chunk_count: u
 }
 
 pub fn clear(addr: &str, rel_path: &str, root: [u8;32]) -> Result<()> {
+    let _guard = STORE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
     let mut store = load_store()?;
     let key_s = key(addr, rel_path, &hex(&root));
-    store.entries.remove(&key_s);
+    // Most finalized files never had an entry; skip rewriting the store for them
+    if store.entries.remove(&key_s).is_none() { return Ok(()); }
     save_store(&store)
 }
 
@@ -90,7 +97,7 @@ fn hex(bytes: &[u8; 32]) -> String {
 }
 
 fn bytes_len(chunk_count: u64) -> usize {
-    ((chunk_count + 7) / 8) as usize
+    chunk_count.div_ceil(8) as usize
 }
 
 fn set_bit(bits: &mut [u8], index: usize) {
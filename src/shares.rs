@@ -0,0 +1,99 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+use crate::{chunk::SymlinkPolicy, protocol::ProtocolError};
+
+/// What a client may do on a share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    /// Pull only; pushes, deletes, renames and new folders are refused.
+    ReadOnly,
+    ReadWrite,
+}
+
+/// One folder a server exposes, with its per-share options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Share {
+    pub path: PathBuf,
+    /// Serve only this file (relative to `path`)
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Nobody may change this share, whatever `peers` says.
+    #[serde(default)]
+    pub read_only: bool,
+    /// Apply the extended attributes clients push (`user.*` only). Off by default,
+    /// since xattrs can carry labels and flags a client should not get to set here.
+    #[serde(default)]
+    pub xattrs: bool,
+    /// Client certificate fingerprints (hex) allowed on this share. Empty means any
+    /// client may read and write, as before access control existed.
+    #[serde(default)]
+    pub peers: BTreeMap<String, Access>,
+}
+
+impl Share {
+    /// The access a client with certificate fingerprint `peer` has here.
+    pub fn access(&self, name: &str, peer: Option<&str>) -> Result<Access, ProtocolError> {
+        let cap = |a: Access| if self.read_only { Access::ReadOnly } else { a };
+        if self.peers.is_empty() { return Ok(cap(Access::ReadWrite)); }
+        let found = peer.and_then(|fp| self.peers.iter().find(|(k, _)| normalize_fingerprint(k) == normalize_fingerprint(fp)));
+        match (found, peer) {
+            (Some((_, access)), _) => Ok(cap(*access)),
+            (None, Some(fp)) => Err(ProtocolError::AccessDenied(format!("client {fp} is not allowed on share {name:?}"))),
+            (None, None) => Err(ProtocolError::AccessDenied(format!("share {name:?} requires a client certificate"))),
+        }
+    }
+}
+
+/// Fingerprints are compared case-insensitively and with or without `:` separators.
+pub fn normalize_fingerprint(fp: &str) -> String {
+    fp.chars().filter(|c| *c != ':').collect::<String>().to_ascii_lowercase()
+}
+
+/// Named shares, as read from a server config:
+/// `{ "shares": { "photos": { "path": "/srv/photos", "symlinks": "skip" } } }`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Shares {
+    pub shares: BTreeMap<String, Share>,
+}
+
+impl Shares {
+    /// A server exposing exactly one folder, as `serve <folder>` does.
+    pub fn single(share: Share) -> Shares {
+        Shares { shares: BTreeMap::from([(share_name(&share.path), share)]) }
+    }
+
+    pub fn load(path: &Path) -> Result<Shares> {
+        let data = fs::read(path).with_context(|| format!("read {path:?}"))?;
+        let shares: Shares = serde_json::from_slice(&data).with_context(|| format!("parse {path:?}"))?;
+        if shares.shares.is_empty() { bail!("{path:?} declares no shares"); }
+        for (name, share) in &shares.shares {
+            if !share.path.is_dir() { bail!("share {name:?}: {:?} is not a directory", share.path); }
+        }
+        Ok(shares)
+    }
+
+    /// The share a client asked for in `Hello`, and what that client may do there. An
+    /// empty name picks the only share when there is just one, so single-folder servers
+    /// need no name.
+    pub fn resolve(&self, name: &str, peer: Option<&str>) -> Result<(Share, Access), ProtocolError> {
+        let found = if name.is_empty() && self.shares.len() == 1 { self.shares.iter().next() } else { self.shares.get_key_value(name) };
+        let Some((name, share)) = found else {
+            let known: Vec<&str> = self.shares.keys().map(|k| k.as_str()).collect();
+            let what = if name.is_empty() { "no share given".to_string() } else { format!("unknown share {name:?}") };
+            return Err(ProtocolError::UnknownShare(format!("{what}; this server has: {}", known.join(", "))));
+        };
+        let access = share.access(name, peer)?;
+        Ok((share.clone(), access))
+    }
+}
+
+fn share_name(path: &Path) -> String {
+    path.canonicalize().ok().as_deref().unwrap_or(path)
+        .file_name().map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_else(|| "default".to_string())
+}
@@ -1,20 +1,38 @@
 use chrono::{DateTime, Utc};
 use once_cell::sync::OnceCell;
 use serde::Serialize;
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::{atomic::{AtomicU64, Ordering}, Arc}};
 
 pub static STATUS: OnceCell<Arc<tokio::sync::Mutex<SyncStatus>>> = OnceCell::new();
 
+/// Chunk payload bytes moved in either direction: before compression, and as sent.
+static BYTES_RAW: AtomicU64 = AtomicU64::new(0);
+static BYTES_WIRE: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Default, Clone, Serialize)]
 pub struct SyncStatus {
     pub last_event: Option<String>,
     pub last_sync_ok: Option<bool>,
     pub last_sync_time: Option<DateTime<Utc>>,
     pub active: bool,
+    /// What is being transferred: one file's name, or the first of several with a count.
+    /// Received and total bytes are summed over all of them.
     pub current_file: Option<String>,
     pub current_received: u64,
     pub current_total: u64,
+    /// Files in flight as (received, total) bytes; several pull at once.
+    #[serde(skip)]
+    transfers: BTreeMap<String, (u64, u64)>,
     pub last_message: Option<String>,
+    pub bytes_raw: u64,
+    pub bytes_wire: u64,
+    /// Whether the client has a connection to the server; `reconnecting` is set while it
+    /// waits to retry one that dropped.
+    pub connected: bool,
+    pub reconnecting: bool,
+    pub reconnect_attempt: u32,
+    pub reconnect_in_secs: Option<f64>,
+    pub last_error: Option<String>,
 }
 
 pub fn init(handle: Arc<tokio::sync::Mutex<SyncStatus>>) {
@@ -30,29 +48,81 @@ pub async fn set_active(active: bool) {
     }
 }
 
+pub async fn connected() {
+    if let Some(h) = STATUS.get() {
+        let mut s = h.lock().await;
+        s.connected = true;
+        s.reconnecting = false;
+        s.reconnect_attempt = 0;
+        s.reconnect_in_secs = None;
+        s.last_event = Some("connected".into());
+        s.last_sync_time = Some(Utc::now());
+    }
+}
+
+pub async fn reconnecting(attempt: u32, delay: std::time::Duration, error: &str) {
+    if let Some(h) = STATUS.get() {
+        let mut s = h.lock().await;
+        s.active = false;
+        s.connected = false;
+        s.reconnecting = true;
+        s.reconnect_attempt = attempt;
+        s.reconnect_in_secs = Some(delay.as_secs_f64());
+        s.last_error = Some(error.to_string());
+        s.last_event = Some("reconnecting".into());
+        s.transfers.clear();
+        s.sum_transfers();
+        s.last_sync_time = Some(Utc::now());
+    }
+}
+
+/// The client session ended (stopped, refused, or failed for good).
+pub async fn disconnected() {
+    if let Some(h) = STATUS.get() {
+        let mut s = h.lock().await;
+        s.connected = false;
+        s.reconnecting = false;
+        s.reconnect_in_secs = None;
+    }
+}
+
+impl SyncStatus {
+    /// Recompute the `current_*` fields from the files in flight.
+    fn sum_transfers(&mut self) {
+        self.current_file = match self.transfers.keys().next() {
+            None => None,
+            Some(first) if self.transfers.len() == 1 => Some(first.clone()),
+            Some(first) => Some(format!("{} (+{} more)", first, self.transfers.len() - 1)),
+        };
+        self.current_received = self.transfers.values().map(|t| t.0).sum();
+        self.current_total = self.transfers.values().map(|t| t.1).sum();
+    }
+}
+
 pub async fn start_file(name: &str, total: u64) {
     if let Some(h) = STATUS.get() {
         let mut s = h.lock().await;
-        s.current_file = Some(name.to_string());
-        s.current_total = total;
-        s.current_received = 0;
+        s.transfers.insert(name.to_string(), (0, total));
+        s.sum_transfers();
         s.last_event = Some("file_started".into());
         s.last_sync_time = Some(Utc::now());
     }
 }
 
-pub async fn progress(received: u64) {
+pub async fn progress(name: &str, received: u64) {
     if let Some(h) = STATUS.get() {
         let mut s = h.lock().await;
-        s.current_received = received;
+        if let Some(t) = s.transfers.get_mut(name) { t.0 = received; }
+        s.sum_transfers();
         s.last_event = Some("progress".into());
         s.last_sync_time = Some(Utc::now());
     }
 }
 
-pub async fn file_done(ok: bool, msg: &str) {
+pub async fn file_done(name: &str, ok: bool, msg: &str) {
     if let Some(h) = STATUS.get() {
         let mut s = h.lock().await;
+        if s.transfers.remove(name).is_some() { s.sum_transfers(); }
         s.last_sync_ok = Some(ok);
         s.last_message = Some(msg.to_string());
         s.last_event = Some("file_done".into());
@@ -67,9 +137,19 @@ pub async fn session_done(ok: bool, msg: &str) {
         s.last_sync_ok = Some(ok);
         s.last_message = Some(msg.to_string());
         s.last_event = Some("session_done".into());
-        s.current_file = None;
-        s.current_total = 0;
-        s.current_received = 0;
+        s.transfers.clear();
+        s.sum_transfers();
         s.last_sync_time = Some(Utc::now());
     }
 }
+
+/// Count one chunk payload; `wire` is its size as sent (smaller when compressed).
+pub fn chunk_bytes(raw: u64, wire: u64) {
+    BYTES_RAW.fetch_add(raw, Ordering::Relaxed);
+    BYTES_WIRE.fetch_add(wire, Ordering::Relaxed);
+}
+
+/// Raw and on-the-wire chunk bytes since the process started.
+pub fn chunk_totals() -> (u64, u64) {
+    (BYTES_RAW.load(Ordering::Relaxed), BYTES_WIRE.load(Ordering::Relaxed))
+}
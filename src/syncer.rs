@@ -1,56 +1,232 @@
-use crate::{chunk::{chunk_file, rel_paths_in_dir, ChunkInfo, write_chunk, CHUNK_SIZE}, merkle::{build_merkle, root_hash}, protocol::{FileSummary}};
+use crate::{chunk::{dir_root, entries_for, hash_bytes, link_root, walk_share, ChunkInfo, Chunking, SymlinkPolicy, write_at, CHUNK_SIZE}, index, merkle::ShareTree, protocol::{ChunkRange, EntryKind, FileAttrs, FileSummary}, relpath::RelPath};
 use anyhow::{Result};
-use std::path::{Path, PathBuf};
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}};
 
-pub fn build_file_summary(root: &Path, rel: &Path) -> Result<(FileSummary, Vec<ChunkInfo>)> {
+pub fn build_file_summary(root: &Path, rel: &Path, chunking: Chunking) -> Result<(FileSummary, Vec<ChunkInfo>)> {
     let abs = root.join(rel);
     let meta = std::fs::metadata(&abs)?;
-    let chunks = chunk_file(&abs)?;
-    let tree = build_merkle(&chunks);
-    let root_hash_v = root_hash(&tree);
+    let rel_s = rel.to_string_lossy().to_string();
+    let (chunks, root_hash_v) = index::chunk_file_cached(root, &rel_s, chunking)?;
     Ok((FileSummary {
-        rel_path: rel.to_string_lossy().to_string(),
+        rel_path: rel_s,
         size: meta.len(),
         chunk_count: chunks.len() as u64,
         root: root_hash_v,
+        attrs: attrs_from_meta(&meta),
+        kind: EntryKind::File,
     }, chunks))
 }
 
-pub fn all_summaries(root: &Path) -> Result<Vec<(FileSummary, Vec<ChunkInfo>)>> {
+pub fn link_summary(rel_path: String, target: String) -> FileSummary {
+    FileSummary { rel_path, size: target.len() as u64, chunk_count: 0, root: link_root(&target), attrs: FileAttrs::default(), kind: EntryKind::Symlink(target) }
+}
+
+pub fn dir_summary(rel_path: String) -> FileSummary {
+    FileSummary { rel_path, size: 0, chunk_count: 0, root: dir_root(), attrs: FileAttrs::default(), kind: EntryKind::Dir }
+}
+
+/// Chunks and root of whatever is at `rel` under `symlinks`: a regular file (hashed via
+/// the index), a directory (`dir_root`) or an unfollowed symlink (`link_root` of its
+/// target); the latter two have no chunks. None if absent.
+pub fn local_entry(root: &Path, rel: &RelPath, chunking: Chunking, symlinks: SymlinkPolicy) -> Result<Option<(Vec<ChunkInfo>, [u8; 32])>> {
+    let abs = rel.to_path(root);
+    let Ok(meta) = std::fs::symlink_metadata(&abs) else { return Ok(None) };
+    if meta.file_type().is_symlink() && symlinks != SymlinkPolicy::Follow {
+        let target = std::fs::read_link(&abs)?;
+        return Ok(Some((Vec::new(), link_root(&target.to_string_lossy()))));
+    }
+    if abs.is_dir() { return Ok(Some((Vec::new(), dir_root()))); }
+    if !abs.is_file() { return Ok(None); }
+    Ok(Some(index::chunk_file_cached(root, rel, chunking)?))
+}
+
+/// Replace whatever is at `rel` with a symlink to `target`.
+pub fn make_symlink(root: &Path, rel: &RelPath, target: &str) -> Result<()> {
+    let abs = rel.to_path(root);
+    if let Some(p) = abs.parent() { std::fs::create_dir_all(p)?; }
+    if std::fs::symlink_metadata(&abs).is_ok() { std::fs::remove_file(&abs)?; }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, &abs)?;
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_file(target, &abs)?;
+    Ok(())
+}
+
+/// Only permission bits travel; setuid, setgid and sticky never cross to a peer.
+const MODE_MASK: u32 = 0o777;
+
+/// Only the `user.*` namespace is synced. `security.*`, `trusted.*` and `system.*` hold
+/// labels, capabilities and ACLs that mean nothing (or too much) on another machine.
+fn synced_xattr(name: &str) -> bool {
+    name.starts_with("user.")
+}
+
+pub fn attrs_from_meta(meta: &std::fs::Metadata) -> FileAttrs {
+    #[cfg(unix)]
+    let mode = { use std::os::unix::fs::PermissionsExt; Some(meta.permissions().mode() & MODE_MASK) };
+    #[cfg(not(unix))]
+    let mode = None;
+    FileAttrs { mtime_ns: index::stamp(meta).mtime_ns, mode, xattrs: Vec::new() }
+}
+
+/// mtime and mode of `path`, plus its `user.*` extended attributes when `with_xattrs` is set.
+pub fn read_attrs(path: &Path, with_xattrs: bool) -> Result<FileAttrs> {
+    let mut attrs = attrs_from_meta(&std::fs::metadata(path)?);
+    #[cfg(unix)]
+    if with_xattrs {
+        for name in xattr::list(path)? {
+            let name = name.to_string_lossy().to_string();
+            if !synced_xattr(&name) { continue; }
+            if let Some(value) = xattr::get(path, &name)? { attrs.xattrs.push((name, value)); }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = with_xattrs;
+    Ok(attrs)
+}
+
+/// Apply mtime, xattrs (if `with_xattrs`) and finally mode to `path`; the mode goes last
+/// since it may drop our own write access. Failures are reported but not fatal: the
+/// content is already in place.
+pub fn apply_attrs(path: &Path, attrs: &FileAttrs, with_xattrs: bool) {
+    if attrs.mtime_ns > 0 {
+        let t = std::time::UNIX_EPOCH + std::time::Duration::from_nanos(attrs.mtime_ns as u64);
+        let res = std::fs::OpenOptions::new().write(true).open(path).and_then(|f| f.set_modified(t));
+        if let Err(e) = res { eprintln!("set mtime on {path:?}: {e}"); }
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if with_xattrs {
+            for (name, value) in attrs.xattrs.iter().filter(|(n, _)| synced_xattr(n)) {
+                if let Err(e) = xattr::set(path, name, value) { eprintln!("xattr {name} on {path:?}: {e}"); }
+            }
+        }
+        if let Some(mode) = attrs.mode {
+            if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & MODE_MASK)) {
+                eprintln!("chmod {path:?}: {e}");
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = with_xattrs;
+}
+
+pub fn all_summaries(root: &Path, chunking: Chunking, symlinks: SymlinkPolicy) -> Result<Vec<(FileSummary, Vec<ChunkInfo>)>> {
     let mut out = Vec::new();
-    for rel in rel_paths_in_dir(root)? {
-        let (s, chunks) = build_file_summary(root, &rel)?;
+    let entries = walk_share(root, symlinks)?;
+    for rel in entries.files {
+        let (s, chunks) = build_file_summary(root, &rel, chunking)?;
         out.push((s, chunks));
     }
+    // Trash entries stay cached too: they feed the chunk locator
+    let mut live: HashSet<String> = out.iter().map(|(s, _)| s.rel_path.clone()).collect();
+    for (rel, target) in entries.links {
+        out.push((link_summary(rel.to_string_lossy().to_string(), target), Vec::new()));
+    }
+    // Deepest first, so a pass empties a directory before it considers removing it
+    let mut dirs = entries.dirs;
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    for rel in dirs {
+        out.push((dir_summary(rel.to_string_lossy().to_string()), Vec::new()));
+    }
+    live.extend(trash_rel_paths(root));
+    index::retain(root, &live);
     Ok(out)
 }
 
-pub fn diff_needed_indices(local_chunks: &[ChunkInfo], remote_chunk_hashes: &[[u8; 32]]) -> Vec<u64> {
-    // Compare chunk-by-chunk hashes
-    let mut need = Vec::new();
-    for (i, remote_h) in remote_chunk_hashes.iter().enumerate() {
-        let miss = match local_chunks.get(i) {
-            Some(c) => &c.hash != remote_h,
-            None => true,
-        };
-        if miss { need.push(i as u64); }
+/// Like `all_summaries`, but only for the given paths (e.g. the ones a watcher saw
+/// change); see `chunk::entries_for`, which drops any path that is not a valid `RelPath`.
+pub fn summaries_for<'a>(root: &Path, rels: impl IntoIterator<Item = &'a String>, chunking: Chunking, symlinks: SymlinkPolicy) -> Vec<(FileSummary, Vec<ChunkInfo>)> {
+    let entries = entries_for(root, rels, symlinks);
+    let mut out: Vec<_> = entries.files.iter().filter_map(|rel| build_file_summary(root, rel, chunking).ok()).collect();
+    for (rel, target) in entries.links {
+        out.push((link_summary(rel.to_string_lossy().to_string(), target), Vec::new()));
     }
-    need
+    let mut dirs = entries.dirs;
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    out.extend(dirs.into_iter().map(|d| (dir_summary(d.to_string_lossy().to_string()), Vec::new())));
+    out
+}
+
+/// Directory-level Merkle tree over what `walk_share` lists, file roots from the hash
+/// index. Symlinks and empty directories only count with `links`/`dirs`, as they only
+/// appear in a session's summary with the `symlinks`/`dirs` capabilities.
+pub fn share_tree(root: &Path, chunking: Chunking, symlinks: SymlinkPolicy, links: bool, dirs: bool) -> Result<ShareTree> {
+    let entries = walk_share(root, symlinks)?;
+    let mut items = Vec::new();
+    for rel in &entries.files {
+        let rel_s = rel.to_string_lossy().replace('\\', "/");
+        if let Ok((_, r)) = index::chunk_file_cached(root, &rel_s, chunking) { items.push((rel_s, r)); }
+    }
+    for (rel, target) in entries.links.iter().filter(|_| links) {
+        items.push((rel.to_string_lossy().replace('\\', "/"), link_root(target)));
+    }
+    let dirs = entries.dirs.iter().filter(|_| dirs).map(|d| d.to_string_lossy().replace('\\', "/"));
+    Ok(ShareTree::build(items, dirs))
+}
+
+/// Relative paths (e.g. `.leafsync_trash/<ts>/a.txt`) of every file in the share's trash.
+pub fn trash_rel_paths(root: &Path) -> Vec<String> {
+    let trash = root.join(".leafsync_trash");
+    if !trash.is_dir() { return Vec::new(); }
+    ignore::WalkBuilder::new(&trash).standard_filters(false).build()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .filter_map(|e| e.path().strip_prefix(root).ok().map(|p| p.to_string_lossy().replace('\\', "/")))
+        .collect()
+}
+
+/// Where each chunk hash can be read from locally: any file in the share or its trash.
+/// Locations can go stale as files change; readers re-hash what they copy.
+#[derive(Default)]
+pub struct ChunkLocator {
+    by_hash: HashMap<[u8; 32], (PathBuf, u64, u32)>,
+}
+
+impl ChunkLocator {
+    /// Index `files` (relative path and chunks, as from `all_summaries`) plus the trash.
+    pub fn build<'a>(root: &Path, chunking: Chunking, files: impl IntoIterator<Item = (&'a str, &'a [ChunkInfo])>) -> ChunkLocator {
+        let mut loc = ChunkLocator::default();
+        for (rel, chunks) in files { loc.add(root.join(rel), chunks); }
+        for rel in trash_rel_paths(root) {
+            if let Ok((chunks, _)) = index::chunk_file_cached(root, &rel, chunking) { loc.add(root.join(&rel), &chunks); }
+        }
+        loc
+    }
+
+    pub fn add(&mut self, abs: PathBuf, chunks: &[ChunkInfo]) {
+        for c in chunks {
+            self.by_hash.entry(c.hash).or_insert_with(|| (abs.clone(), c.offset, c.size));
+        }
+    }
+}
+
+/// How many of `remote_chunks` also occur (by hash) in `local_chunks`.
+pub fn shared_chunks(local_chunks: &[ChunkInfo], remote_chunks: &[ChunkInfo]) -> usize {
+    let have: HashSet<[u8; 32]> = local_chunks.iter().map(|c| c.hash).collect();
+    remote_chunks.iter().filter(|c| have.contains(&c.hash)).count()
+}
+
+/// Byte ranges (in the remote layout) for the given remote chunk indices.
+pub fn ranges_for(remote_chunks: &[ChunkInfo], indices: &[u64]) -> Vec<ChunkRange> {
+    let want: HashSet<u64> = indices.iter().copied().collect();
+    remote_chunks.iter().filter(|c| want.contains(&c.index)).map(ChunkRange::from).collect()
 }
 
 #[allow(dead_code)]
-pub fn apply_chunk(root: &Path, rel_path: &str, index: u64, data: &[u8]) -> Result<()> {
-    let abs = root.join(rel_path);
+pub fn apply_chunk(root: &Path, rel_path: &RelPath, offset: u64, data: &[u8]) -> Result<()> {
+    let abs = rel_path.to_path(root);
     if let Some(parent) = abs.parent() { std::fs::create_dir_all(parent)?; }
-    write_chunk(&abs, index, data)?;
+    write_at(&abs, offset, data)?;
     Ok(())
 }
 
 /// Ensure the file is truncated to the expected number of chunks.
 #[allow(dead_code)]
-pub fn truncate_to_chunks(root: &Path, rel_path: &str, chunk_count: u64, last_chunk_size: Option<usize>) -> Result<()> {
+pub fn truncate_to_chunks(root: &Path, rel_path: &RelPath, chunk_count: u64, last_chunk_size: Option<usize>) -> Result<()> {
     use std::io::{Seek, SeekFrom, Write};
-    let abs = root.join(rel_path);
+    let abs = rel_path.to_path(root);
     if !abs.exists() { return Ok(()); }
     let mut f = std::fs::OpenOptions::new().read(true).write(true).open(&abs)?;
     let expected_size = if chunk_count == 0 { 0 } else { (chunk_count - 1) * CHUNK_SIZE as u64 + last_chunk_size.unwrap_or(CHUNK_SIZE) as u64 };
@@ -62,67 +238,94 @@ pub fn truncate_to_chunks(root: &Path, rel_path: &str, chunk_count: u64, last_ch
 
 /// Truncate or extend the file to an exact byte size.
 #[allow(dead_code)]
-pub fn truncate_to_size(root: &Path, rel_path: &str, size: u64) -> Result<()> {
-    let abs = root.join(rel_path);
+pub fn truncate_to_size(root: &Path, rel_path: &RelPath, size: u64) -> Result<()> {
+    let abs = rel_path.to_path(root);
     if !abs.exists() { return Ok(()); }
     let f = std::fs::OpenOptions::new().write(true).open(&abs)?;
     f.set_len(size)?;
     Ok(())
 }
 
-// Atomic finalize support
-pub fn staging_path(root: &Path, rel_path: &str) -> PathBuf {
+// Atomic finalize support. `.part` is appended, not swapped for the extension, so
+// `foo.c` and `foo.h` pulled at the same time never share a staging file.
+pub fn staging_path(root: &Path, rel_path: &RelPath) -> PathBuf {
     let tmp_dir = root.join(".leafsync_tmp");
-    tmp_dir.join(rel_path).with_extension("part")
+    tmp_dir.join(format!("{rel_path}.part"))
 }
 
-pub fn apply_chunk_staging(root: &Path, rel_path: &str, index: u64, data: &[u8]) -> Result<PathBuf> {
+pub fn apply_chunk_staging(root: &Path, rel_path: &RelPath, offset: u64, data: &[u8]) -> Result<PathBuf> {
     let stage = staging_path(root, rel_path);
     if let Some(parent) = stage.parent() { std::fs::create_dir_all(parent)?; }
-    write_chunk(&stage, index, data)?;
+    write_at(&stage, offset, data)?;
     Ok(stage)
 }
 
-pub fn finalize_staging(root: &Path, rel_path: &str) -> Result<()> {
+pub fn finalize_staging(root: &Path, rel_path: &RelPath) -> Result<()> {
     let stage = staging_path(root, rel_path);
-    let final_path = root.join(rel_path);
+    let final_path = rel_path.to_path(root);
     if let Some(parent) = final_path.parent() { std::fs::create_dir_all(parent)?; }
     if final_path.exists() { std::fs::remove_file(&final_path)?; }
     std::fs::rename(stage, final_path)?;
     Ok(())
 }
 
-#[allow(dead_code)]
-pub fn path_from_rel(root: &Path, rel: &str) -> PathBuf {
-    root.join(rel)
-}
-
 /// Truncate or extend the staged file to an exact byte size.
-pub fn truncate_staging_to_size(root: &Path, rel_path: &str, size: u64) -> Result<()> {
+pub fn truncate_staging_to_size(root: &Path, rel_path: &RelPath, size: u64) -> Result<()> {
     let stage = staging_path(root, rel_path);
     if let Some(parent) = stage.parent() { std::fs::create_dir_all(parent)?; }
-    let f = std::fs::OpenOptions::new().create(true).write(true).open(&stage)?;
+    let f = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&stage)?;
     f.set_len(size)?;
     Ok(())
 }
 
-/// Seed the staging file with the current destination file contents, if any.
-/// This avoids zero-filled gaps causing Merkle mismatches during server-side push.
-pub fn seed_staging_from_dest(root: &Path, rel_path: &str) -> Result<()> {
-    use std::io::{Read, Write};
-    let final_path = root.join(rel_path);
+/// Seed the staging file with every remote chunk we already hold locally, copied to the
+/// offset the remote layout expects. `src` (usually the current destination file) is
+/// tried first, then anything `others` knows about. Returns the remote chunk indices
+/// now in staging; chunks that are already there (an interrupted transfer) are rewritten.
+pub fn seed_staging(root: &Path, rel_path: &RelPath, src: &Path, local_chunks: &[ChunkInfo], remote_chunks: &[ChunkInfo], others: Option<&ChunkLocator>) -> Result<HashSet<u64>> {
+    use std::io::{Read, Seek, SeekFrom, Write};
     let stage = staging_path(root, rel_path);
     if let Some(parent) = stage.parent() { std::fs::create_dir_all(parent)?; }
-    if !final_path.exists() {
-        // Ensure empty staging file exists
-        let _ = std::fs::OpenOptions::new().create(true).write(true).open(&stage)?;
-        return Ok(());
-    }
-    // Copy whole file into staging
-    let mut src = std::fs::File::open(&final_path)?;
-    let mut dst = std::fs::OpenOptions::new().create(true).write(true).truncate(true).open(&stage)?;
+    let mut dst = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&stage)?;
+    let mut first = ChunkLocator::default();
+    if src.exists() { first.add(src.to_path_buf(), local_chunks); }
+    let mut files: HashMap<PathBuf, Option<std::fs::File>> = HashMap::new();
+    let mut filled = HashSet::new();
     let mut buf = Vec::new();
-    src.read_to_end(&mut buf)?;
-    dst.write_all(&buf)?;
+    for rc in remote_chunks {
+        let found = first.by_hash.get(&rc.hash).or_else(|| others.and_then(|o| o.by_hash.get(&rc.hash)));
+        let Some((path, offset, size)) = found else { continue };
+        let Some(f) = files.entry(path.clone()).or_insert_with(|| std::fs::File::open(path).ok()) else { continue };
+        buf.resize(*size as usize, 0);
+        if f.seek(SeekFrom::Start(*offset)).is_err() || f.read_exact(&mut buf).is_err() { continue; }
+        // The file may have changed since it was hashed
+        if hash_bytes(&buf) != rc.hash { continue; }
+        dst.seek(SeekFrom::Start(rc.offset))?;
+        dst.write_all(&buf)?;
+        filled.insert(rc.index);
+    }
+    Ok(filled)
+}
+
+/// Remove `rel` if it is an empty directory. Ok(false) if it still has entries.
+pub fn remove_empty_dir(root: &Path, rel: &RelPath) -> Result<bool> {
+    let abs = rel.to_path(root);
+    if std::fs::read_dir(&abs)?.next().is_some() { return Ok(false); }
+    std::fs::remove_dir(&abs)?;
+    Ok(true)
+}
+
+/// Safe delete: move `rel` under `.leafsync_trash/<timestamp>/` instead of removing it.
+pub fn move_to_trash(root: &Path, rel: &RelPath) -> Result<()> {
+    use chrono::Local;
+    let ts = Local::now().format("%Y%m%d_%H%M%S").to_string();
+    let base = root.join(".leafsync_trash").join(ts);
+    let from = rel.to_path(root);
+    let to = rel.to_path(&base);
+    if let Some(p) = to.parent() { std::fs::create_dir_all(p)?; }
+    // symlink_metadata: a dangling symlink is still something to move
+    if std::fs::symlink_metadata(&from).is_ok() {
+        std::fs::rename(&from, &to)?;
+    }
     Ok(())
 }
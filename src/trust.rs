@@ -16,7 +16,7 @@ pub fn load() -> Result<TrustStore> {
     let p = trust_path()?;
     if !p.exists() { return Ok(TrustStore::default()); }
     let data = fs::read(&p).with_context(|| format!("read {p:?}"))?;
-    Ok(serde_json::from_slice(&data).with_context(|| "parse trust.json")?)
+    serde_json::from_slice(&data).with_context(|| "parse trust.json")
 }
 
 pub fn save(store: &TrustStore) -> Result<()> {
@@ -0,0 +1,94 @@
+use anyhow::Result;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{collections::HashSet, path::{Path, PathBuf}, time::Duration};
+use tokio::{sync::mpsc, time::{sleep_until, Instant}};
+
+use crate::chunk::is_ignored;
+
+/// Quiet period after the last event before a batch is handed out.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+/// A folder that never goes quiet (a long copy) still gets synced this often.
+const MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// Filesystem notifications for a share, batched into sets of changed relative paths.
+pub struct FolderWatcher {
+    root: PathBuf,
+    tx: mpsc::UnboundedSender<PathBuf>,
+    rx: mpsc::UnboundedReceiver<PathBuf>,
+    // Dropping the watcher stops the notifications
+    _watcher: RecommendedWatcher,
+}
+
+impl FolderWatcher {
+    pub fn new(root: &Path) -> Result<FolderWatcher> {
+        // Watch the canonical path so event paths strip cleanly against it
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        let (tx, rx) = mpsc::unbounded_channel();
+        let events = tx.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            let Ok(event) = res else { return };
+            if matches!(event.kind, EventKind::Access(_)) { return; }
+            for p in event.paths { let _ = events.send(p); }
+        })?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+        Ok(FolderWatcher { root, tx, rx, _watcher: watcher })
+    }
+
+    /// Feeds share-relative paths that changed elsewhere (the server's `Changed`) into
+    /// the same batches as local events. Callers validate the paths first.
+    pub fn injector(&self) -> impl Fn(&str) + Send + 'static {
+        let (tx, root) = (self.tx.clone(), self.root.clone());
+        move |rel| { let _ = tx.send(root.join(rel)); }
+    }
+
+    /// Wait for local changes and return the affected paths (plus their parent folders)
+    /// once they settle. Returns None when `rescan` passes without any, meaning it is
+    /// time for a full pass.
+    pub async fn next_batch(&mut self, rescan: Duration) -> Option<HashSet<String>> {
+        let mut batch = HashSet::new();
+        let deadline = Instant::now() + rescan;
+        let mut first: Option<Instant> = None;
+        loop {
+            let wake = match first {
+                None => deadline,
+                Some(f) => (Instant::now() + DEBOUNCE).min(f + MAX_DELAY),
+            };
+            tokio::select! {
+                p = self.rx.recv() => match p {
+                    Some(p) => {
+                        if let Some(rel) = self.rel(&p) {
+                            first.get_or_insert_with(Instant::now);
+                            batch.insert(rel);
+                        }
+                    }
+                    // The watcher is gone; fall back to polling
+                    None => { sleep_until(deadline).await; return None; }
+                },
+                _ = sleep_until(wake) => {
+                    first?;
+                    return Some(with_parents(batch));
+                }
+            }
+        }
+    }
+
+    /// Share-relative path for an event, or None for our own staging/trash and ignored paths.
+    fn rel(&self, p: &Path) -> Option<String> {
+        let rel = p.strip_prefix(&self.root).ok()?;
+        let rel = rel.to_string_lossy().replace('\\', "/");
+        if rel.is_empty() || crate::net::is_internal_rel(&rel) || is_ignored(&self.root, &rel) { return None; }
+        Some(rel)
+    }
+}
+
+fn with_parents(batch: HashSet<String>) -> HashSet<String> {
+    let mut all = batch.clone();
+    for p in &batch {
+        let mut cur = p.as_str();
+        while let Some((parent, _)) = cur.rsplit_once('/') {
+            if !all.insert(parent.to_string()) { break; }
+            cur = parent;
+        }
+    }
+    all
+}
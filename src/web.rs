@@ -13,19 +13,16 @@ pub struct AppState {
 type SyncStatus = crate::status::SyncStatus;
 
 #[derive(Deserialize)]
-struct ServeReq { folder: String, port: u16, rel_file: Option<String> }
+struct ServeReq { folder: String, port: u16, rel_file: Option<String>, symlinks: Option<String> }
 
 #[derive(Deserialize)]
-struct ConnectReq { addr: String, folder: String, accept_first: bool, fingerprint: Option<String>, rel_file: Option<String>, mirror: Option<bool>, streams: Option<usize>, rate_mbps: Option<f64> }
+struct ConnectReq { addr: String, folder: String, accept_first: bool, fingerprint: Option<String>, rel_file: Option<String>, mirror: Option<bool>, streams: Option<usize>, parallel_files: Option<usize>, rate_mbps: Option<f64>, chunking: Option<String>, xattrs: Option<bool>, symlinks: Option<String>, share: Option<String>, direction: Option<String>, rescan_secs: Option<u64> }
 
 #[derive(Serialize)]
 struct Resp { ok: bool, msg: String }
 
 // Watch removed
 
-#[derive(Deserialize)]
-struct StopReq {}
-
 #[derive(Serialize)]
 struct DirEntry { name: String, path: String, has_children: bool }
 
@@ -94,6 +91,7 @@ async fn index() -> axum::response::Html<&'static str> {
     label{font-size:.85rem;color:var(--muted)}
     :root{--control-h:44px;--gap:12px;--fs:14px}
     input[type="text"], input[type="number"], input[type="password"], input[type="search"]{width:100%;background:#0b1226;color:var(--fg);border:1px solid var(--border);border-radius:10px;padding:10px 12px;outline:none;height:var(--control-h);line-height:calc(var(--control-h) - 2px);box-sizing:border-box;font-size:var(--fs);transition:border-color .15s, box-shadow .15s}
+    select{width:100%;background:#0b1226;color:var(--fg);border:1px solid var(--border);border-radius:10px;padding:0 12px;height:var(--control-h);box-sizing:border-box;font-size:var(--fs)}
     input[type="text"]:focus, input[type="number"]:focus{border-color:var(--primary-2);box-shadow:0 0 0 3px rgba(99,102,241,.25)}
     .controls{display:grid;grid-template-columns:1fr 140px;gap:var(--gap)}
     .controls-3{display:grid;grid-template-columns:1fr 1fr auto;gap:var(--gap)}
@@ -144,15 +142,15 @@ async fn index() -> axum::response::Html<&'static str> {
     let pickerTarget=null; let fileTarget=null; let currentPath=''; let selectedFile='';
     const LS={get:(k,d)=>{try{const v=localStorage.getItem(k);return v==null?d:JSON.parse(v)}catch{return d}},set:(k,v)=>{try{localStorage.setItem(k,JSON.stringify(v))}catch{}}}
     function restoreInputs(){
-      const m=[["serve-folder",""],["serve-port","4455"],["serve-file",""],["connect-addr","127.0.0.1:4455"],["connect-folder",""],["connect-file",""],["fingerprint",""],["connect-streams","4"],["connect-rate",""]];
+      const m=[["serve-folder",""],["serve-port","4455"],["serve-file",""],["connect-addr","127.0.0.1:4455"],["connect-folder",""],["connect-file",""],["fingerprint",""],["connect-streams","4"],["connect-rate",""],["connect-chunking","fixed"],["connect-share",""],["connect-direction","both"]];
       for(const [id,def] of m){const el=$(id); if(el){ const v=LS.get('ls:'+id,def); if(v!==undefined&&v!==null) el.value=v; }}
-      const cb=[["accept-first",false],["connect-mirror",false]]; for(const [id,def] of cb){const el=$(id); if(el) el.checked=!!LS.get('ls:'+id,def)}
+      const cb=[["accept-first",false],["connect-mirror",false],["connect-xattrs",false]]; for(const [id,def] of cb){const el=$(id); if(el) el.checked=!!LS.get('ls:'+id,def)}
       renderRecents();
     }
     function bindPersist(){
-      const ids=["serve-folder","serve-port","serve-file","connect-addr","connect-folder","connect-file","fingerprint","connect-streams","connect-rate"];
+      const ids=["serve-folder","serve-port","serve-file","connect-addr","connect-folder","connect-file","fingerprint","connect-streams","connect-rate","connect-chunking","connect-share","connect-direction"];
       for(const id of ids){ const el=$(id); if(el) el.addEventListener('input',()=>LS.set('ls:'+id, el.value)); }
-      const cbs=["accept-first","connect-mirror"]; for(const id of cbs){ const el=$(id); if(el) el.addEventListener('change',()=>LS.set('ls:'+id, el.checked)); }
+      const cbs=["accept-first","connect-mirror","connect-xattrs"]; for(const id of cbs){ const el=$(id); if(el) el.addEventListener('change',()=>LS.set('ls:'+id, el.checked)); }
     }
     function addRecent(addr,folder){ if(!addr||!folder) return; const key='ls:recents'; let r=LS.get(key,[]); const item={addr,folder,ts:Date.now()}; r=r.filter(x=>!(x.addr===addr&&x.folder===folder)); r.unshift(item); if(r.length>6) r=r.slice(0,6); LS.set(key,r); renderRecents(); }
     function renderRecents(){ const key='ls:recents'; const r=LS.get(key,[]); const el=$('recents'); if(!el) return; el.innerHTML=''; for(const it of r){ const chip=document.createElement('div'); chip.className='chip'; chip.title=`${it.addr} → ${it.folder}`; chip.textContent=it.addr; chip.onclick=()=>{ $('connect-addr').value=it.addr; $('connect-folder').value=it.folder; LS.set('ls:connect-addr',it.addr); LS.set('ls:connect-folder',it.folder); }; el.appendChild(chip);} if(r.length===0){ const t=document.createElement('div'); t.className='hint'; t.textContent='No recent connections yet.'; el.appendChild(t);} }
@@ -169,9 +167,9 @@ async fn index() -> axum::response::Html<&'static str> {
     function chooseCurrent(){ if(!pickerTarget||!currentPath){toast('No folder selected');return} $(pickerTarget).value=currentPath; hidePicker(); }
     function chooseFile(){ if(!fileTarget){ return; } if(!selectedFile){ toast('Select a file'); return; } if(!pickerTarget||!currentPath){ toast('No folder selected'); return; } $(pickerTarget).value=currentPath; $(fileTarget).value = relPath(currentPath, selectedFile); hidePicker(); }
     async function serve(){ const folder = $('serve-folder').value.trim(); const port = parseInt($('serve-port').value||'4455'); const rel_file = ($('serve-file')?.value.trim()||'')||null; if(!folder){toast('Folder is required');return} $('serve-btn').disabled=true; const r = await fetch('/api/serve',{method:'POST',headers:{'content-type':'application/json'},body:JSON.stringify({folder,port,rel_file})}); const j = await r.json(); $('serve-out').textContent=j.msg; $('serve-btn').disabled=false; toast('Server starting'); LS.set('ls:serve-folder', folder); LS.set('ls:serve-port', String(port)); if(rel_file) LS.set('ls:serve-file', rel_file); }
-    async function connectPeer(){ let addr=$('connect-addr').value.trim(); const folder=$('connect-folder').value.trim(); const accept_first=$('accept-first').checked; const fingerprint=$('fingerprint').value.trim()||null; const rel_file=($('connect-file')?.value.trim()||'')||null; const mirror=$('connect-mirror')?.checked||false; const streams=parseInt($('connect-streams')?.value||'4'); const rate_mbps=parseFloat($('connect-rate')?.value||''); const rate = isNaN(rate_mbps)? null : rate_mbps; if(addr && !addr.includes(':')) addr = addr+':4455'; if(!addr||!folder){toast('Address and local folder are required');return} $('connect-btn').disabled=true; const r=await fetch('/api/connect',{method:'POST',headers:{'content-type':'application/json'},body:JSON.stringify({addr,folder,accept_first,fingerprint,rel_file,mirror,streams,rate_mbps:rate})}); const j=await r.json(); $('connect-out').textContent=j.msg; $('connect-btn').disabled=false; toast('Connect started'); LS.set('ls:connect-addr', addr); LS.set('ls:connect-folder', folder); if(rel_file) LS.set('ls:connect-file', rel_file); LS.set('ls:accept-first', accept_first); LS.set('ls:connect-mirror', mirror); LS.set('ls:fingerprint', fingerprint||''); LS.set('ls:connect-streams', String(streams)); if(rate!=null) LS.set('ls:connect-rate', String(rate)); addRecent(addr, folder); }
+    async function connectPeer(){ let addr=$('connect-addr').value.trim(); const folder=$('connect-folder').value.trim(); const accept_first=$('accept-first').checked; const fingerprint=$('fingerprint').value.trim()||null; const rel_file=($('connect-file')?.value.trim()||'')||null; const mirror=$('connect-mirror')?.checked||false; const streams=parseInt($('connect-streams')?.value||'4'); const rate_mbps=parseFloat($('connect-rate')?.value||''); const rate = isNaN(rate_mbps)? null : rate_mbps; const chunking=$('connect-chunking')?.value||'fixed'; const xattrs=$('connect-xattrs')?.checked||false; const share=$('connect-share')?.value.trim()||null; const direction=$('connect-direction')?.value||'both'; if(addr && !addr.includes(':')) addr = addr+':4455'; if(!addr||!folder){toast('Address and local folder are required');return} $('connect-btn').disabled=true; const r=await fetch('/api/connect',{method:'POST',headers:{'content-type':'application/json'},body:JSON.stringify({addr,folder,accept_first,fingerprint,rel_file,mirror,streams,rate_mbps:rate,chunking,xattrs,share,direction})}); const j=await r.json(); $('connect-out').textContent=j.msg; $('connect-btn').disabled=false; toast('Connect started'); LS.set('ls:connect-addr', addr); LS.set('ls:connect-folder', folder); if(rel_file) LS.set('ls:connect-file', rel_file); LS.set('ls:accept-first', accept_first); LS.set('ls:connect-mirror', mirror); LS.set('ls:fingerprint', fingerprint||''); LS.set('ls:connect-streams', String(streams)); if(rate!=null) LS.set('ls:connect-rate', String(rate)); addRecent(addr, folder); }
   async function stopSession(){ try{ const r=await fetch('/api/stop',{method:'POST'}); const j=await r.json(); toast(j.msg||'Stopped'); $('connect-out').textContent=j.msg||'Stopped'; }catch{ toast('Failed to stop'); } }
-    async function refreshStatus(){ try{ const r=await fetch('/api/status'); const s=await r.json(); $('active').textContent = s.active?'true':'false'; $('file').textContent = s.current_file||'-'; const rec=s.current_received||0, tot=s.current_total||0; const pct=tot>0?Math.min(100,Math.max(0,(rec*100.0)/tot)):0; $('bar-fill').style.width=pct.toFixed(1)+'%'; const bl=$('bar-label'); if(bl) bl.textContent = (tot>0? pct.toFixed(0)+'%':''); $('progress').textContent=`${fmtBytes(rec)} / ${fmtBytes(tot)} (${pct.toFixed(1)}%)`; const now=performance.now(); if(lastTs>0 && rec>=lastBytes){const dt=(now-lastTs)/1000.0; const db=rec-lastBytes; const mbps=(db/dt)/1024/1024; if(isFinite(mbps)) $('speed').textContent=`${mbps.toFixed(2)} MB/s`} lastBytes=rec; lastTs=now; const ev=s.last_event||'-'; const ok=s.last_sync_ok; const when=s.last_sync_time||''; const msg=s.last_message||''; const text = `${ev}${ok==null?'':(' ok='+ok)}${when?(' at '+when):''}${msg?(' ('+msg+')'):''}`; $('last').textContent = text; appendLog(text, ok===true? 'ok' : ok===false? 'err' : 'log'); }catch{} }
+    async function refreshStatus(){ try{ const r=await fetch('/api/status'); const s=await r.json(); $('active').textContent = s.active?'true':'false'; $('conn').textContent = s.reconnecting? `reconnecting in ${(s.reconnect_in_secs||0).toFixed(1)}s (attempt ${s.reconnect_attempt}): ${s.last_error||''}` : (s.connected?'connected':'-'); $('file').textContent = s.current_file||'-'; const rec=s.current_received||0, tot=s.current_total||0; const pct=tot>0?Math.min(100,Math.max(0,(rec*100.0)/tot)):0; $('bar-fill').style.width=pct.toFixed(1)+'%'; const bl=$('bar-label'); if(bl) bl.textContent = (tot>0? pct.toFixed(0)+'%':''); $('progress').textContent=`${fmtBytes(rec)} / ${fmtBytes(tot)} (${pct.toFixed(1)}%)`; const now=performance.now(); if(lastTs>0 && rec>=lastBytes){const dt=(now-lastTs)/1000.0; const db=rec-lastBytes; const mbps=(db/dt)/1024/1024; if(isFinite(mbps)) $('speed').textContent=`${mbps.toFixed(2)} MB/s`} lastBytes=rec; lastTs=now; const raw=s.bytes_raw||0, wire=s.bytes_wire||0; $('wire').textContent = raw>0? `${fmtBytes(raw)} as ${fmtBytes(wire)} on the wire (${(raw/Math.max(wire,1)).toFixed(1)}x)` : '-'; const ev=s.last_event||'-'; const ok=s.last_sync_ok; const when=s.last_sync_time||''; const msg=s.last_message||''; const text = `${ev}${ok==null?'':(' ok='+ok)}${when?(' at '+when):''}${msg?(' ('+msg+')'):''}`; $('last').textContent = text; appendLog(text, ok===true? 'ok' : ok===false? 'err' : 'log'); }catch{} }
     function appendLog(line, cls){
       const out=$('console');
       if(!out||!line) return;
@@ -238,13 +236,17 @@ async fn index() -> axum::response::Html<&'static str> {
           <summary>Advanced</summary>
           <div class="controls-3" style="margin-top:10px">
             <label><input type="checkbox" id="accept-first"/> Accept first</label>
-            <label><input type="checkbox" id="connect-mirror"/> Mirror deletes</label>
+            <span><label><input type="checkbox" id="connect-mirror"/> Mirror deletes</label> <label><input type="checkbox" id="connect-xattrs"/> Sync xattrs</label></span>
             <input id="fingerprint" type="text" placeholder="Fingerprint (hex, optional)" />
           </div>
+          <div class="controls-3">
+            <input id="connect-share" type="text" placeholder="Share name (if the server has several)" />
+            <select id="connect-direction" title="Direction"><option value="both">Two-way sync</option><option value="pull">Pull only (backup)</option><option value="push">Push only (deploy)</option></select>
+          </div>
           <div class="controls-3">
             <input id="connect-streams" type="number" min="1" max="16" value="4" placeholder="Streams (1-16)" />
             <input id="connect-rate" type="number" min="0" step="0.1" placeholder="Rate limit (Mbps, optional)" />
-            <div></div>
+            <select id="connect-chunking" title="Chunking"><option value="fixed">Fixed 1 MiB chunks</option><option value="fastcdc">Content-defined (FastCDC)</option></select>
           </div>
           <div class="controls">
             <input id="connect-file" type="text" placeholder="Specific file to sync (relative to folder, optional)" />
@@ -264,10 +266,12 @@ async fn index() -> axum::response::Html<&'static str> {
       <div class="card stack">
         <h3>Status</h3>
         <div class="row kpi"><div>Active:</div><div><b id="active">false</b></div></div>
+        <div class="row kpi"><div>Connection:</div><div><b id="conn">-</b></div></div>
         <div class="row kpi"><div>File:</div><div><b id="file">-</b></div></div>
         <div class="bar"><div id="bar-fill"></div><div id="bar-label"></div></div>
         <div class="row kpi"><div>Progress:</div><div><b id="progress">0 / 0</b></div></div>
         <div class="row kpi"><div>Speed:</div><div><b id="speed">0 MB/s</b></div></div>
+        <div class="row kpi"><div>Transferred:</div><div><b id="wire">-</b></div></div>
         <div class="row kpi"><div>Last:</div><div><b id="last">-</b></div></div>
         <div class="console" id="console" aria-label="Activity log"></div>
       </div>
@@ -298,16 +302,49 @@ async fn index() -> axum::response::Html<&'static str> {
 
 async fn api_serve(State(_state): State<Arc<AppState>>, Json(req): Json<ServeReq>) -> Json<Resp> {
     let folder = PathBuf::from(req.folder);
+    let symlinks = match parse_symlinks(req.symlinks.as_deref()) {
+      Ok(s) => s,
+      Err(e) => return Json(Resp { ok: false, msg: format!("Invalid symlink policy: {e}") }),
+    };
     tokio::spawn(async move {
-  if let Err(e) = crate::net::run_server_filtered(folder, req.port, req.rel_file).await {
+  if let Err(e) = crate::net::run_server_filtered(folder, req.port, req.rel_file, symlinks).await {
             eprintln!("server error: {e:?}");
         }
     });
     Json(Resp { ok: true, msg: format!("Server starting on 0.0.0.0:{}", req.port) })
 }
 
+fn parse_symlinks(s: Option<&str>) -> anyhow::Result<crate::chunk::SymlinkPolicy> {
+    Ok(s.filter(|s| !s.trim().is_empty()).map(str::parse).transpose()?.unwrap_or_default())
+}
+
 async fn api_connect(State(state): State<Arc<AppState>>, Json(req): Json<ConnectReq>) -> Json<Resp> {
     let folder = PathBuf::from(req.folder);
+    let chunking = match req.chunking.as_deref().filter(|s| !s.trim().is_empty()).map(str::parse::<crate::chunk::Chunking>).transpose() {
+      Ok(c) => c.unwrap_or_default(),
+      Err(e) => return Json(Resp { ok: false, msg: format!("Invalid chunking: {e}") }),
+    };
+    let symlinks = match parse_symlinks(req.symlinks.as_deref()) {
+      Ok(s) => s,
+      Err(e) => return Json(Resp { ok: false, msg: format!("Invalid symlink policy: {e}") }),
+    };
+    let direction = match req.direction.as_deref().filter(|s| !s.trim().is_empty()).map(str::parse::<crate::protocol::Direction>).transpose() {
+      Ok(d) => d.unwrap_or_default(),
+      Err(e) => return Json(Resp { ok: false, msg: format!("Invalid direction: {e}") }),
+    };
+    let opts = crate::net::SyncOptions {
+      only_file: req.rel_file,
+      mirror: req.mirror.unwrap_or(false),
+      streams: req.streams.unwrap_or(4),
+      parallel_files: req.parallel_files.unwrap_or(4),
+      rate_mbps: req.rate_mbps,
+      chunking,
+      xattrs: req.xattrs.unwrap_or(false),
+      symlinks,
+      share: req.share.unwrap_or_default(),
+      direction,
+      rescan_secs: req.rescan_secs.unwrap_or(30),
+    };
     // Abort any existing session
     {
       let mut h = state.runner.lock().await;
@@ -320,13 +357,11 @@ async fn api_connect(State(state): State<Arc<AppState>>, Json(req): Json<Connect
           folder,
           req.accept_first,
           req.fingerprint,
-          req.rel_file,
-          req.mirror.unwrap_or(false),
-          req.streams.unwrap_or(4),
-          req.rate_mbps,
+          opts,
         ).await {
               eprintln!("client error: {e:?}");
           }
+          crate::status::disconnected().await;
     });
     {
       let mut h = state.runner.lock().await; *h = Some(handle);
@@ -337,7 +372,9 @@ async fn api_connect(State(state): State<Arc<AppState>>, Json(req): Json<Connect
 // watch endpoints removed
 
 async fn api_status(State(state): State<Arc<AppState>>) -> Json<SyncStatus> {
-  Json(state.status.lock().await.clone())
+  let mut s = state.status.lock().await.clone();
+  (s.bytes_raw, s.bytes_wire) = crate::status::chunk_totals();
+  Json(s)
 }
 
 async fn api_stop(State(state): State<Arc<AppState>>) -> Json<Resp> {
@@ -348,6 +385,7 @@ async fn api_stop(State(state): State<Arc<AppState>>) -> Json<Resp> {
   }
   // reflect in status
   crate::status::session_done(false, "stopped by user").await;
+  crate::status::disconnected().await;
   Json(Resp{ ok: true, msg: if aborted { "Session stopped".into() } else { "No active session".into() } })
 }
 
@@ -386,36 +424,34 @@ async fn api_fs_list(Query(q): Query<PathQuery>) -> Json<FsListResp> {
   let mut dirs: Vec<DirEntry> = Vec::new();
   let mut files: Vec<FileEntry> = Vec::new();
   if let Ok(rd) = std::fs::read_dir(&path) {
-    for e in rd {
-      if let Ok(entry) = e {
-        if let Ok(ft) = entry.file_type() {
-          if ft.is_dir() {
-            let p = entry.path();
-            // Fast child-dir probe (up to a handful)
-            let mut has_children = false;
-            if let Ok(mut it) = std::fs::read_dir(&p) {
-              for _ in 0..8 { // cap to 8 entries
-                if let Some(Ok(ch)) = it.next() {
-                  if ch.file_type().map(|ft| ft.is_dir()).unwrap_or(false) { has_children = true; break; }
-                } else { break; }
-              }
+    for entry in rd.flatten() {
+      if let Ok(ft) = entry.file_type() {
+        if ft.is_dir() {
+          let p = entry.path();
+          // Fast child-dir probe (up to a handful)
+          let mut has_children = false;
+          if let Ok(mut it) = std::fs::read_dir(&p) {
+            for _ in 0..8 { // cap to 8 entries
+              if let Some(Ok(ch)) = it.next() {
+                if ch.file_type().map(|ft| ft.is_dir()).unwrap_or(false) { has_children = true; break; }
+              } else { break; }
             }
-            let name = entry.file_name().to_string_lossy().to_string();
-            let path_str = p.to_string_lossy().to_string();
-            dirs.push(DirEntry{ name, path: path_str, has_children });
-          } else if ft.is_file() {
-            let name = entry.file_name().to_string_lossy().to_string();
-            let p = entry.path();
-            let path_str = p.to_string_lossy().to_string();
-            let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
-            files.push(FileEntry{ name, path: path_str, size });
           }
+          let name = entry.file_name().to_string_lossy().to_string();
+          let path_str = p.to_string_lossy().to_string();
+          dirs.push(DirEntry{ name, path: path_str, has_children });
+        } else if ft.is_file() {
+          let name = entry.file_name().to_string_lossy().to_string();
+          let p = entry.path();
+          let path_str = p.to_string_lossy().to_string();
+          let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+          files.push(FileEntry{ name, path: path_str, size });
         }
       }
     }
   }
-  dirs.sort_by(|a,b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
-  files.sort_by(|a,b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+  dirs.sort_by_key(|a| a.name.to_lowercase());
+  files.sort_by_key(|a| a.name.to_lowercase());
   Json(FsListResp{ path: path.to_string_lossy().to_string(), dirs, files })
 }
 